@@ -0,0 +1,273 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::error::AppError;
+use teloxide::{
+    prelude::{AutoSend, Bot},
+    requests::Requester,
+    types::BotCommand as MenuCommand,
+    utils::command::{BotCommand, ParseError},
+};
+
+// Command name and description, in menu order.
+const COMMANDS: [(&str, &str); 12] = [
+    ("subscribe", "subscribe this group to the reminder"),
+    ("unsubscribe", "stop sending reminders to this group"),
+    ("status", "show the reminder settings of this group"),
+    (
+        "timezone",
+        "show or set the timezone, e.g. /timezone Asia/Hong_Kong",
+    ),
+    (
+        "schedule",
+        "show or set the schedule, e.g. /schedule 10:30,15:00 on Mon-Fri",
+    ),
+    (
+        "catchup",
+        "show or set the catch-up policy: skip, late <minutes> or always",
+    ),
+    (
+        "packs",
+        "show or choose the voice packs, e.g. /packs cantonese-classic mandarin",
+    ),
+    (
+        "extra",
+        "show or choose a sticker, GIF or photo sent with each reminder, e.g. /extra sticker gif",
+    ),
+    (
+        "addvoice",
+        "add a clip to the library, as the caption of a voice or audio file (owners only)",
+    ),
+    (
+        "submit",
+        "suggest a clip for the library, reply to a voice message with /submit <title>",
+    ),
+    ("help", "show this help"),
+    ("test", "send a reminder right now (debug builds only)"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Subscribe,
+    Unsubscribe,
+    Status,
+    Timezone(String),
+    Schedule(String),
+    CatchUp(String),
+    Packs(String),
+    Extra(String),
+    AddVoice(String),
+    Submit(String),
+    Help,
+    #[cfg(debug_assertions)]
+    Test,
+}
+
+impl Command {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Subscribe => "subscribe",
+            Command::Unsubscribe => "unsubscribe",
+            Command::Status => "status",
+            Command::Timezone(_) => "timezone",
+            Command::Schedule(_) => "schedule",
+            Command::CatchUp(_) => "catchup",
+            Command::Packs(_) => "packs",
+            Command::Extra(_) => "extra",
+            Command::AddVoice(_) => "addvoice",
+            Command::Submit(_) => "submit",
+            Command::Help => "help",
+            #[cfg(debug_assertions)]
+            Command::Test => "test",
+        }
+    }
+    /// Whether the command changes the chat's settings, these are for administrators only.
+    pub(crate) fn is_config(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe
+                | Command::Unsubscribe
+                | Command::Timezone(_)
+                | Command::Schedule(_)
+                | Command::CatchUp(_)
+                | Command::Packs(_)
+                | Command::Extra(_)
+        )
+    }
+}
+
+impl BotCommand for Command {
+    fn descriptions() -> String {
+        let lines: Vec<String> = menu()
+            .map(|(name, description)| format!("/{} - {}", name, description))
+            .collect();
+        format!("These commands are supported:\n{}", lines.join("\n"))
+    }
+
+    // "/name@bot args" or "/name @bot args", the mention is optional but must match the bot
+    // when present.
+    fn parse<N>(s: &str, bot_name: N) -> Result<Self, ParseError>
+    where
+        N: Into<String>,
+    {
+        let bot_name = bot_name.into();
+        let mut words = s.trim().splitn(2, char::is_whitespace);
+        let head = words.next().unwrap_or_default();
+        let mut args = String::from(words.next().unwrap_or_default().trim());
+
+        let head = match head.strip_prefix('/') {
+            Some(head) => head,
+            None => return Err(ParseError::UnknownCommand(String::from(head))),
+        };
+        let (name, mention) = match head.split_once('@') {
+            Some((name, mention)) => (name, Some(String::from(mention))),
+            // In groups with several bots the mention may follow as the first argument.
+            None => match args.strip_prefix('@') {
+                Some(rest) => {
+                    let mut rest = rest.splitn(2, char::is_whitespace);
+                    let mention = String::from(rest.next().unwrap_or_default());
+                    args = String::from(rest.next().unwrap_or_default().trim());
+                    (head, Some(mention))
+                }
+                None => (head, None),
+            },
+        };
+        if let Some(mention) = mention {
+            if !mention.eq_ignore_ascii_case(&bot_name) {
+                return Err(ParseError::WrongBotName(mention));
+            }
+        }
+
+        // Commands without arguments ignore trailing words.
+        match name.to_ascii_lowercase().as_str() {
+            "subscribe" => Ok(Command::Subscribe),
+            "unsubscribe" => Ok(Command::Unsubscribe),
+            "status" => Ok(Command::Status),
+            "timezone" => Ok(Command::Timezone(args)),
+            "schedule" => Ok(Command::Schedule(args)),
+            "catchup" => Ok(Command::CatchUp(args)),
+            "packs" => Ok(Command::Packs(args)),
+            "extra" => Ok(Command::Extra(args)),
+            "addvoice" => Ok(Command::AddVoice(args)),
+            "submit" => Ok(Command::Submit(args)),
+            "help" | "start" => Ok(Command::Help),
+            #[cfg(debug_assertions)]
+            "test" => Ok(Command::Test),
+            _ => Err(ParseError::UnknownCommand(format!("/{}", name))),
+        }
+    }
+}
+
+// Owner only commands are left out, groups have no use for them.
+fn menu() -> impl Iterator<Item = (&'static str, &'static str)> {
+    COMMANDS
+        .iter()
+        .filter(|(name, _)| *name != "addvoice")
+        .filter(|(name, _)| cfg!(debug_assertions) || *name != "test")
+        .copied()
+}
+
+/// Registers the commands with Telegram so clients show them in the command menu.
+pub async fn set_bot_commands(bot: &AutoSend<Bot>) -> Result<(), AppError> {
+    let commands = menu().map(|(name, description)| MenuCommand::new(name, description));
+    bot.set_my_commands(commands).await?;
+    log::trace!("Bot commands registered!");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Option<Command> {
+        Command::parse(s, "YinChaBot").ok()
+    }
+
+    #[test]
+    fn parses_commands_with_arguments() {
+        assert_eq!(parse("/subscribe"), Some(Command::Subscribe));
+        assert_eq!(parse("/Status"), Some(Command::Status));
+        assert_eq!(
+            parse("/timezone  Asia/Hong_Kong "),
+            Some(Command::Timezone(String::from("Asia/Hong_Kong")))
+        );
+        assert_eq!(
+            parse("/schedule 10:30,15:00 on Mon-Fri"),
+            Some(Command::Schedule(String::from("10:30,15:00 on Mon-Fri")))
+        );
+        assert_eq!(parse("/catchup"), Some(Command::CatchUp(String::new())));
+        assert_eq!(parse("/start"), Some(Command::Help));
+    }
+
+    #[test]
+    fn ignores_trailing_words_without_arguments() {
+        assert_eq!(parse("/subscribe now"), Some(Command::Subscribe));
+    }
+
+    #[test]
+    fn checks_the_bot_mention() {
+        assert_eq!(parse("/help@yinchabot"), Some(Command::Help));
+        assert_eq!(parse("/subscribe @YinChaBot"), Some(Command::Subscribe));
+        assert_eq!(
+            parse("/timezone @YinChaBot UTC+8"),
+            Some(Command::Timezone(String::from("UTC+8")))
+        );
+        assert!(matches!(
+            Command::parse("/subscribe @OtherBot", "YinChaBot"),
+            Err(ParseError::WrongBotName(name)) if name == "OtherBot"
+        ));
+        assert!(matches!(
+            Command::parse("/help@OtherBot", "YinChaBot"),
+            Err(ParseError::WrongBotName(name)) if name == "OtherBot"
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert!(matches!(
+            Command::parse("/tea", "YinChaBot"),
+            Err(ParseError::UnknownCommand(name)) if name == "/tea"
+        ));
+        assert_eq!(parse("subscribe"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn only_settings_commands_are_config() {
+        assert!(Command::Subscribe.is_config());
+        assert!(Command::Packs(String::new()).is_config());
+        assert!(!Command::Status.is_config());
+        assert!(!Command::Submit(String::new()).is_config());
+        assert!(!Command::AddVoice(String::new()).is_config());
+    }
+
+    #[test]
+    fn every_command_is_described() {
+        for (name, _) in COMMANDS.iter() {
+            if !cfg!(debug_assertions) && *name == "test" {
+                continue;
+            }
+            let command = parse(&format!("/{} args", name)).unwrap();
+            assert_eq!(command.name(), *name);
+        }
+    }
+
+    #[test]
+    fn menu_leaves_out_owner_commands() {
+        assert!(menu().all(|(name, _)| name != "addvoice"));
+        assert!(menu().any(|(name, _)| name == "submit"));
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{schedule::ScheduleError, settings::CatchUpError, timezone::TimezoneError};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    io,
+    time::Duration,
+};
+use teloxide::{ApiError, DownloadError, RequestError};
+
+#[derive(Debug)]
+pub enum AppError {
+    /// Missing environment variables or invalid configuration values.
+    Config(String),
+    Io(io::Error),
+    /// Settings or ledger files that cannot be located, read or written.
+    Storage(String),
+    Api(RequestError),
+    Media(String),
+}
+
+/// How a failed send to one chat should be handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendFailure {
+    /// The bot can no longer post to the chat, its subscription should be deactivated.
+    ChatGone,
+    /// The group was upgraded to a supergroup with this id, resend there.
+    Migrated(i64),
+    /// Worth retrying, after `retry_after` when Telegram asked for a delay.
+    Transient {
+        retry_after: Option<Duration>,
+    },
+    Permanent,
+}
+
+impl AppError {
+    pub fn send_failure(&self) -> SendFailure {
+        let error = match self {
+            AppError::Api(error) => error,
+            _ => return SendFailure::Permanent,
+        };
+        match error {
+            RequestError::ApiError { kind, .. } => match kind {
+                ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::BotBlocked
+                | ApiError::ChatNotFound
+                | ApiError::GroupDeactivated
+                | ApiError::UserDeactivated
+                | ApiError::CantInitiateConversation => SendFailure::ChatGone,
+                ApiError::Unknown(description) if description.starts_with("Forbidden:") => {
+                    SendFailure::ChatGone
+                }
+                _ => SendFailure::Permanent,
+            },
+            RequestError::RetryAfter(secs) => SendFailure::Transient {
+                retry_after: Some(Duration::from_secs((*secs).max(1) as u64)),
+            },
+            RequestError::NetworkError(_) | RequestError::InvalidJson(_) => {
+                SendFailure::Transient { retry_after: None }
+            }
+            RequestError::MigrateToChatId(chat_id) => SendFailure::Migrated(*chat_id),
+            RequestError::Io(_) => SendFailure::Permanent,
+        }
+    }
+}
+
+impl AppError {
+    /// Telegram refused a cached `file_id`, the file has to be uploaded again.
+    pub fn is_bad_file_id(&self) -> bool {
+        match self {
+            AppError::Api(RequestError::ApiError { kind, .. }) => match kind {
+                ApiError::WrongFileId | ApiError::FileIdInvalid => true,
+                ApiError::Unknown(description) => description.contains("file identifier"),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            AppError::Config(msg) => write!(f, "AppError::config: {}", msg),
+            AppError::Io(error) => write!(f, "AppError::io: {}", error),
+            AppError::Storage(msg) => write!(f, "AppError::storage: {}", msg),
+            AppError::Api(error) => write!(f, "AppError::api: {}", error),
+            AppError::Media(msg) => write!(f, "AppError::media: {}", msg),
+        }
+    }
+}
+
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AppError::Io(error) => Some(error),
+            AppError::Api(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(error: io::Error) -> Self {
+        AppError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(error: serde_json::Error) -> Self {
+        AppError::Storage(error.to_string())
+    }
+}
+
+impl From<RequestError> for AppError {
+    fn from(error: RequestError) -> Self {
+        AppError::Api(error)
+    }
+}
+
+impl From<DownloadError> for AppError {
+    fn from(error: DownloadError) -> Self {
+        AppError::Media(format!("download failed: {}", error))
+    }
+}
+
+impl From<TimezoneError> for AppError {
+    fn from(error: TimezoneError) -> Self {
+        AppError::Config(error.to_string())
+    }
+}
+
+impl From<ScheduleError> for AppError {
+    fn from(error: ScheduleError) -> Self {
+        AppError::Config(error.to_string())
+    }
+}
+
+impl From<CatchUpError> for AppError {
+    fn from(error: CatchUpError) -> Self {
+        AppError::Config(error.to_string())
+    }
+}
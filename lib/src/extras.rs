@@ -0,0 +1,88 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{
+    error::AppError,
+    media::{choose_voice, Format, Library, Source, Voice, MANIFEST_NAME},
+};
+use once_cell::sync::Lazy;
+use std::{
+    env,
+    fs::metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+// Telegram's upload limits for each kind of extra.
+const MAX_STICKER_SIZE: u64 = 512 * 1024;
+const MAX_ANIMATION_SIZE: u64 = 50 * 1024 * 1024;
+const MAX_PHOTO_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Stickers, GIFs and photos sent along with the reminder, from `BOT_EXTRA_DIR` and its
+/// `manifest.json`.
+pub(crate) static EXTRAS: Source = Source {
+    name: "Extras library",
+    dir: get_extra_dir,
+    manifest: |dir| dir.join(MANIFEST_NAME),
+    recursive: || false,
+    ingest: ingest_extra,
+    formats: &[Format::Sticker, Format::Animation, Format::Photo],
+    catalog: &CATALOG,
+};
+
+// Filled by `check_extra_library` at startup, which scans the directory once.
+static CATALOG: Lazy<RwLock<Arc<Library>>> =
+    Lazy::new(|| RwLock::new(Arc::new(Library::default())));
+
+pub(crate) fn get_extra_dir() -> Result<PathBuf, AppError> {
+    match env::var("BOT_EXTRA_DIR") {
+        Ok(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => Err(AppError::Config(String::from("BOT_EXTRA_DIR not set!"))),
+    }
+}
+
+/// Picks an extra of one of the given kinds, weighted by the manifest. `None` when the chat
+/// wants none or the library has none of these kinds.
+pub(crate) fn choose_extra(kinds: &[Format]) -> Option<Voice> {
+    if kinds.is_empty() {
+        return None;
+    }
+    let library = EXTRAS.snapshot();
+    let matching = library
+        .voices
+        .iter()
+        .filter(|extra| kinds.contains(&extra.format));
+    choose_voice(matching, &[]).ok().cloned()
+}
+
+// The kind of an extra follows from its extension, each kind has its own size limit.
+fn ingest_extra(path: &Path) -> Result<(PathBuf, Format), String> {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let (format, limit) = match extension.as_str() {
+        "webp" | "tgs" => (Format::Sticker, MAX_STICKER_SIZE),
+        "gif" | "mp4" => (Format::Animation, MAX_ANIMATION_SIZE),
+        "jpg" | "jpeg" | "png" => (Format::Photo, MAX_PHOTO_SIZE),
+        _ => return Err(String::from("not a sticker, GIF or photo")),
+    };
+    let size = metadata(path).map_err(|_| String::from("not found"))?.len();
+    if size > limit {
+        return Err(format!("{} bytes exceeds the {} bytes limit", size, limit));
+    }
+    Ok((path.to_path_buf(), format))
+}
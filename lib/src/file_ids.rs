@@ -0,0 +1,98 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{error::AppError, utils::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// Telegram `file_id`s of uploaded voice clips, keyed by the clip's content hash so a renamed
+/// file keeps its id and an edited one is uploaded again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileIds {
+    #[serde(default)]
+    files: HashMap<String, String>,
+    #[serde(skip)]
+    file_path: String,
+}
+
+impl FileIds {
+    pub fn new() -> Result<Self, AppError> {
+        let f = Self::make()?;
+        f.save()?;
+        Ok(f)
+    }
+    pub(crate) fn save(&self) -> Result<(), AppError> {
+        write_atomic(Path::new(&self.file_path), &serde_json::to_vec(&self)?)
+    }
+    pub(crate) fn get(&self, hash: &str) -> Option<&String> {
+        self.files.get(hash)
+    }
+    pub(crate) fn insert(&mut self, hash: String, file_id: String) -> &Self {
+        self.files.insert(hash, file_id);
+        self
+    }
+    pub(crate) fn forget(&mut self, hash: &str) -> &Self {
+        self.files.remove(hash);
+        self
+    }
+    fn make() -> Result<Self, AppError> {
+        let name = "FileIds.json";
+        let dirs = get_setting_dir()?;
+        let dir = dirs.cache_dir();
+        let path = format!("{}/{}", dir.display(), name);
+        let is_exists = check_exists_and_create(Path::new(&path), b"{}")?;
+
+        let mut file_ids = if is_exists {
+            let mut f = File::open(&path)?;
+            let mut buffer = String::new();
+            f.read_to_string(&mut buffer)?;
+            serde_json::from_str::<FileIds>(&buffer)?
+        } else {
+            FileIds {
+                files: HashMap::new(),
+                file_path: String::new(),
+            }
+        };
+        file_ids.file_path = path;
+        Ok(file_ids)
+    }
+}
+
+/// 64-bit FNV-1a hash of the file's content, with its length, as hex. Stable across builds,
+/// unlike the std hasher.
+pub(crate) fn content_hash(path: &Path) -> Result<String, AppError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = [0u8; 8192];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut len: u64 = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        len += read as u64;
+    }
+    Ok(format!("{:016x}-{:x}", hash, len))
+}
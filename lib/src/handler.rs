@@ -15,71 +15,1132 @@
  */
 
 use crate::{
-    media::{get_random_voice, get_voice_dir},
-    settings::{Settings, Subscribe},
+    command::Command,
+    error::{AppError, SendFailure},
+    extras::choose_extra,
+    file_ids::FileIds,
+    media::{add_clip, catalog, choose_voice, mark_played, Clip, Format, Voice},
+    schedule::Schedule,
+    scheduler::SchedulerHandle,
+    settings::{CatchUp, Settings, Subscribe},
+    shuffle::{save_in_background, ShuffleBags},
+    submissions::{Submission, Submissions, MAX_PENDING_PER_USER},
+    throttle::Throttle,
+    timezone::Zone,
+    utils::{check_sender_is_admin, check_user_is_admin, is_owner, owners},
 };
-use chrono::Timelike;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use std::{path::Path, sync::Mutex};
 use teloxide::{
+    net::Download,
+    payloads::{
+        AnswerCallbackQuerySetters, CopyMessageSetters, EditMessageCaptionSetters,
+        SendAnimationSetters, SendAudioSetters, SendMessageSetters, SendPhotoSetters,
+        SendVoiceSetters,
+    },
     prelude::{AutoSend, UpdateWithCx},
-    requests::{Requester, ResponseResult},
-    types::{InputFile, Message},
+    requests::Requester,
+    types::{
+        CallbackQuery, ChatMemberUpdated, InlineKeyboardButton, InlineKeyboardMarkup, InputFile,
+        Message,
+    },
+    utils::command::BotCommand,
     Bot,
 };
 
-pub async fn message_handler(msg: UpdateWithCx<AutoSend<Bot>, Message>) -> ResponseResult<()> {
+const TIMEZONE_EXAMPLES: &str =
+    "UTC+8, UTC-03:30, Europe/London, America/Vancouver, Asia/Singapore";
+const NOT_SUBSCRIBED: &str = "This group is not subscribed yet.";
+const SUBSCRIBE_CALLBACK: &str = "subscribe";
+// Followed by ':' and the submission id.
+const APPROVE_CALLBACK: &str = "approve";
+const REJECT_CALLBACK: &str = "reject";
+// Largest file the Bot API lets bots download.
+const MAX_DOWNLOAD_SIZE: u64 = 20 * 1024 * 1024;
+
+// Loaded once, every send of a broadcast shares it.
+static FILE_IDS: Lazy<Mutex<FileIds>> = Lazy::new(|| {
+    Mutex::new(FileIds::new().unwrap_or_else(|error| {
+        log::error!("{} File ids are cached in memory only.", error);
+        FileIds::default()
+    }))
+});
+
+pub async fn message_handler(
+    msg: UpdateWithCx<AutoSend<Bot>, Message>,
+    scheduler: SchedulerHandle,
+    bot_name: String,
+) -> Result<(), AppError> {
     let chat = &msg.update.chat;
-    let t_now = chrono::Utc::now();
-    let t_hour = t_now.hour() + 8; // UTC+8
-    let t_minute = t_now.minute();
-    let t_second = t_now.second();
-    if msg.update.id == 0 {
-        log::trace!("Current time is: {}:{}:{}.", t_hour, t_minute, t_second);
-        if t_hour == 15 && t_minute == 00 && t_second < 11 {
-            voice_handler(&msg.requester, chat.id)
-                .await
-                .expect("AppError::sendVoice");
-        }
-    } else {
-        let text = msg.update.text().unwrap();
-        let from = msg.update.from().unwrap();
-        // let is_admin = check_sender_is_admin(&msg.requester, chat.id).await; // TODO: Check sender is admin.
-
-        if chat.is_group() && text.eq("/subscribe @yinchabot") {
-            let mut set = Settings::new();
-            set.add_sub(Subscribe::new(chat.id)).save();
-            msg.reply_to::<&str>("Successful subscribe this group!")
-                .await
-                .expect("AppError::sendReply");
+    // Upgrading a group sends one service message to the old group and one to the supergroup.
+    if let Some(to) = msg.update.migrate_to_chat_id() {
+        scheduler.migrate(chat.id, to);
+        return Ok(());
+    }
+    if let Some(from) = msg.update.migrate_from_chat_id() {
+        scheduler.migrate(from, chat.id);
+        return Ok(());
+    }
+    // Stickers, photos, service messages and the like carry no command. Clips owners upload and
+    // clips submitted in groups carry theirs in the caption.
+    let text = match msg.update.text().or_else(|| command_caption(&msg.update)) {
+        Some(text) => text,
+        None => return Ok(()),
+    };
+    let command = match Command::parse(text, bot_name) {
+        Ok(command) => command,
+        Err(_) => return Ok(()),
+    };
+    let sender = sender_name(&msg.update);
+    log::trace!(
+        "Received command /{} in chat id: {}.",
+        command.name(),
+        chat.id
+    );
+
+    if command.is_config() && !check_sender_is_admin(&msg.requester, &msg.update).await? {
+        msg.reply_to::<&str>("Only administrators of this group can change the reminders.")
+            .await?;
+        log::info!(
+            "Bot rejected command from non-admin in group id: {}, message sender: {}.",
+            chat.id,
+            sender
+        );
+        return Ok(());
+    }
+
+    let reply = match command {
+        Command::Subscribe if chat.is_group() || chat.is_supergroup() => {
+            let _guard = Settings::lock();
+            let mut set = Settings::new()?;
+            set.add_sub(Subscribe::new(chat.id)).save()?;
+            scheduler.reload();
             log::info!(
-                "Bot successful subscribe group id: {}, message sender: {}, user id: {}.",
+                "Bot successful subscribe group id: {}, message sender: {}.",
                 chat.id,
-                from.first_name,
-                from.id
+                sender
             );
+            String::from("Successful subscribe this group!")
         }
-
+        Command::Subscribe => String::from("Only groups can subscribe to the reminder."),
+        Command::Unsubscribe => {
+            let _guard = Settings::lock();
+            let mut set = Settings::new()?;
+            let removed = set.remove_sub(chat.id);
+            set.save()?;
+            scheduler.reload();
+            match removed {
+                Some(_) => {
+                    log::info!(
+                        "Bot successful unsubscribe group id: {}, message sender: {}.",
+                        chat.id,
+                        sender
+                    );
+                    String::from("Successful unsubscribe this group!")
+                }
+                None => String::from(NOT_SUBSCRIBED),
+            }
+        }
+        Command::Status => match Settings::new()?.get_sub(chat.id) {
+            Some(sub) if !sub.active => String::from(
+                "Reminders of this group are paused, /subscribe again to resume them.",
+            ),
+            Some(sub) => format!(
+                "This group is subscribed.\nTimezone: {}\nSchedule: {}\nCatch-up: {}\nVoice packs: {}\nExtras: {}\nNext reminder: {}",
+                sub.timezone,
+                sub.schedule,
+                sub.catch_up,
+                pack_list(&sub.packs),
+                extra_list(&sub.extras),
+                next_reminder(sub).unwrap_or_else(|| String::from("none"))
+            ),
+            None => String::from(NOT_SUBSCRIBED),
+        },
+        Command::Timezone(arg) => set_timezone(chat.id, &arg, &scheduler)?,
+        Command::Schedule(arg) => set_schedule(chat.id, &arg, &scheduler)?,
+        Command::CatchUp(arg) => set_catch_up(chat.id, &arg, &scheduler)?,
+        Command::Packs(arg) => set_packs(chat.id, &arg, &scheduler)?,
+        Command::Extra(arg) => set_extra(chat.id, &arg, &scheduler)?,
+        Command::Submit(title) => submit(&msg, &title).await?,
+        Command::AddVoice(title) => match add_voice(&msg, &title).await? {
+            Some(reply) => reply,
+            None => return Ok(()),
+        },
+        Command::Help => Command::descriptions(),
         #[cfg(debug_assertions)]
-        if text.contains("/test") {
-            voice_handler(&msg.requester, chat.id)
-                .await
-                .expect("AppError::sendVoice");
+        Command::Test => {
+            let sub = Settings::new()?.get_sub(chat.id).cloned();
+            // One reply outside any broadcast, only its extra needs pacing.
+            let throttle = Throttle::default();
+            voice_handler(&msg.requester, &throttle, chat.id, sub.as_ref(), None).await?;
+            return Ok(());
+        }
+    };
+    msg.reply_to(reply).await?;
+
+    Ok(())
+}
+
+/// Keeps the subscriptions in sync with the groups the bot is actually in: greets a group when
+/// the bot joins or may speak again, and pauses its reminders when the bot is removed or muted.
+pub async fn my_chat_member_handler(
+    upd: UpdateWithCx<AutoSend<Bot>, ChatMemberUpdated>,
+    scheduler: SchedulerHandle,
+) -> Result<(), AppError> {
+    let update = &upd.update;
+    let chat = &update.chat;
+    if !chat.is_group() && !chat.is_supergroup() {
+        return Ok(());
+    }
+    // Voices are media messages, a bot restricted from sending them is as good as gone.
+    let could_send = update.old_chat_member.kind.can_send_media_messages();
+    let can_send = update.new_chat_member.kind.can_send_media_messages();
+
+    if !could_send && can_send {
+        let subscribed = matches!(Settings::new()?.get_sub(chat.id), Some(sub) if sub.active);
+        let greeting = if subscribed {
+            "饮茶先啦! Reminders of this group are back on."
+        } else {
+            "饮茶先啦! I remind this group to drink tea every day.\nAn administrator can subscribe below or with /subscribe."
+        };
+        let mut req = upd.requester.send_message(chat.id, greeting);
+        if !subscribed {
+            req = req.reply_markup(InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::callback(
+                    String::from("Subscribe"),
+                    String::from(SUBSCRIBE_CALLBACK),
+                ),
+            ]]));
+        }
+        req.await?;
+        log::info!(
+            "Bot joined group id: {}, added by: {}, user id: {}.",
+            chat.id,
+            update.from.first_name,
+            update.from.id
+        );
+    } else if could_send && !can_send {
+        let _guard = Settings::lock();
+        let mut set = Settings::new()?;
+        if set.deactivate_sub(chat.id) {
+            set.save()?;
+            scheduler.reload();
+        }
+        log::info!(
+            "Bot removed or restricted in group id: {}, by: {}, user id: {}.",
+            chat.id,
+            update.from.first_name,
+            update.from.id
+        );
+    }
+    Ok(())
+}
+
+pub async fn callback_query_handler(
+    upd: UpdateWithCx<AutoSend<Bot>, CallbackQuery>,
+    scheduler: SchedulerHandle,
+) -> Result<(), AppError> {
+    let query = &upd.update;
+    let data = query.data.as_deref().unwrap_or_default();
+    let review = data
+        .split_once(':')
+        .and_then(|(action, id)| Some((action, id.parse::<u64>().ok()?)));
+    match (&query.message, data, review) {
+        (Some(message), SUBSCRIBE_CALLBACK, _) => subscribe_button(&upd, message, scheduler).await,
+        (Some(message), _, Some((APPROVE_CALLBACK, id))) => {
+            review_button(&upd, message, id, true).await
+        }
+        (Some(message), _, Some((REJECT_CALLBACK, id))) => {
+            review_button(&upd, message, id, false).await
+        }
+        _ => {
+            upd.requester.answer_callback_query(&query.id).await?;
+            Ok(())
         }
     }
+}
+
+async fn subscribe_button(
+    upd: &UpdateWithCx<AutoSend<Bot>, CallbackQuery>,
+    message: &Message,
+    scheduler: SchedulerHandle,
+) -> Result<(), AppError> {
+    let query = &upd.update;
+    let chat_id = message.chat.id;
+
+    if !check_user_is_admin(&upd.requester, chat_id, query.from.id).await? {
+        upd.requester
+            .answer_callback_query(&query.id)
+            .text("Only administrators of this group can subscribe.")
+            .show_alert(true)
+            .await?;
+        log::info!(
+            "Bot rejected subscribe button from non-admin in group id: {}, user id: {}.",
+            chat_id,
+            query.from.id
+        );
+        return Ok(());
+    }
+
+    {
+        let _guard = Settings::lock();
+        let mut set = Settings::new()?;
+        set.add_sub(Subscribe::new(chat_id)).save()?;
+    }
+    scheduler.reload();
+    log::info!(
+        "Bot successful subscribe group id: {}, button pressed by: {}, user id: {}.",
+        chat_id,
+        query.from.first_name,
+        query.from.id
+    );
+    upd.requester
+        .answer_callback_query(&query.id)
+        .text("Successful subscribe this group!")
+        .await?;
+    upd.requester
+        .edit_message_text(chat_id, message.id, "Successful subscribe this group!")
+        .await?;
+    Ok(())
+}
+
+// Only a voice, audio or document is read for a command: any caption an owner sends in private,
+// and a /submit caption in a group. Other media captions are just captions.
+fn command_caption(msg: &Message) -> Option<&str> {
+    let upload = msg.voice().is_some() || msg.audio().is_some() || msg.document().is_some();
+    let from_owner = matches!(msg.from(), Some(user) if is_owner(user.id));
+    let caption = msg.caption().filter(|_| upload)?;
+    if msg.chat.is_private() && from_owner {
+        return Some(caption);
+    }
+    let command = caption.split_whitespace().next().unwrap_or_default();
+    let submits = command.split('@').next() == Some("/submit");
+    if (msg.chat.is_group() || msg.chat.is_supergroup()) && submits {
+        Some(caption)
+    } else {
+        None
+    }
+}
 
-    teloxide::respond(())
+// Anonymous administrators and channels send on behalf of a chat instead of a user.
+fn sender_name(msg: &Message) -> String {
+    if let Some(chat) = msg.sender_chat() {
+        return format!(
+            "{}, chat id: {}",
+            chat.title().unwrap_or("anonymous"),
+            chat.id
+        );
+    }
+    match msg.from() {
+        Some(user) => format!("{}, user id: {}", user.first_name, user.id),
+        None => String::from("unknown"),
+    }
 }
 
-pub async fn voice_handler(bot: &AutoSend<Bot>, chat_id: i64) -> ResponseResult<()> {
-    let voice_dir = get_voice_dir();
-    let voice = get_random_voice(voice_dir);
-    let voice_clone = voice.clone();
-    bot.send_voice(chat_id, InputFile::file(voice))
+fn set_timezone(chat_id: i64, arg: &str, scheduler: &SchedulerHandle) -> Result<String, AppError> {
+    let _guard = Settings::lock();
+    let mut set = Settings::new()?;
+    let sub = match set.get_sub_mut(chat_id) {
+        Some(sub) => sub,
+        None => return Ok(String::from(NOT_SUBSCRIBED)),
+    };
+    if arg.is_empty() {
+        return Ok(format!(
+            "Timezone of this group is: {}.\nUsage: /timezone <zone>, for example: {}",
+            sub.timezone, TIMEZONE_EXAMPLES
+        ));
+    }
+    let zone = match arg.parse::<Zone>() {
+        Ok(zone) => zone,
+        Err(_) => {
+            return Ok(format!(
+                "Unknown timezone '{}'.\nTry an offset or a region name, for example: {}",
+                arg, TIMEZONE_EXAMPLES
+            ))
+        }
+    };
+
+    sub.update_tz(&zone.to_string());
+    let next = next_reminder(sub);
+    set.save()?;
+    scheduler.reload();
+    log::info!("Bot set timezone of group id: {} to {}.", chat_id, zone);
+    Ok(match next {
+        Some(next) => format!(
+            "Timezone of this group is now: {}.\nNext reminder: {}.",
+            zone, next
+        ),
+        None => format!("Timezone of this group is now: {}.", zone),
+    })
+}
+
+fn set_schedule(chat_id: i64, arg: &str, scheduler: &SchedulerHandle) -> Result<String, AppError> {
+    let _guard = Settings::lock();
+    let mut set = Settings::new()?;
+    let sub = match set.get_sub_mut(chat_id) {
+        Some(sub) => sub,
+        None => return Ok(String::from(NOT_SUBSCRIBED)),
+    };
+    if arg.is_empty() {
+        return Ok(format!(
+            "Reminders of this group are scheduled at: {} ({}).\nUsage: /schedule 10:30,15:00 on Mon-Fri",
+            sub.schedule, sub.timezone
+        ));
+    }
+    let schedule = match arg.parse::<Schedule>() {
+        Ok(schedule) => schedule,
+        Err(error) => return Ok(error.to_string()),
+    };
+
+    sub.update_schedule(&schedule);
+    let timezone = sub.timezone.clone();
+    set.save()?;
+    scheduler.reload();
+    log::info!("Bot set schedule of group id: {} to {}.", chat_id, schedule);
+    Ok(format!(
+        "Reminders of this group are now scheduled at: {} ({}).",
+        schedule, timezone
+    ))
+}
+
+fn set_catch_up(chat_id: i64, arg: &str, scheduler: &SchedulerHandle) -> Result<String, AppError> {
+    let _guard = Settings::lock();
+    let mut set = Settings::new()?;
+    let sub = match set.get_sub_mut(chat_id) {
+        Some(sub) => sub,
+        None => return Ok(String::from(NOT_SUBSCRIBED)),
+    };
+    if arg.is_empty() {
+        return Ok(format!(
+            "Catch-up policy of this group is: {}.\nUsage: /catchup skip | late <minutes> | always",
+            sub.catch_up
+        ));
+    }
+    let catch_up = match arg.parse::<CatchUp>() {
+        Ok(catch_up) => catch_up,
+        Err(error) => return Ok(error.to_string()),
+    };
+
+    sub.update_catch_up(catch_up);
+    set.save()?;
+    scheduler.reload();
+    log::info!(
+        "Bot set catch-up policy of group id: {} to {}.",
+        chat_id,
+        catch_up
+    );
+    Ok(format!(
+        "Catch-up policy of this group is now: {}.",
+        catch_up
+    ))
+}
+
+// Approves or rejects a submitted clip from the buttons sent to the owners. Whoever presses
+// first decides, the other owners' buttons then only report that.
+async fn review_button(
+    upd: &UpdateWithCx<AutoSend<Bot>, CallbackQuery>,
+    message: &Message,
+    id: u64,
+    approve: bool,
+) -> Result<(), AppError> {
+    let query = &upd.update;
+    let bot = &upd.requester;
+    if !is_owner(query.from.id) {
+        bot.answer_callback_query(&query.id)
+            .text("Only the owners of this bot can review clips.")
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+    let submission = {
+        let _guard = Submissions::lock();
+        let mut submissions = Submissions::new()?;
+        let submission = submissions.take(id);
+        if submission.is_some() {
+            submissions.save()?;
+        }
+        submission
+    };
+    let submission = match submission {
+        Some(submission) => submission,
+        None => {
+            bot.answer_callback_query(&query.id)
+                .text("This clip was already reviewed.")
+                .await?;
+            bot.edit_message_reply_markup(message.chat.id, message.id)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut queued = false;
+    let outcome = if approve {
+        match promote(&submission).await {
+            Ok(voice) => {
+                let thanks = format!(
+                    "Thanks {}! \"{}\" was added to the voice library.",
+                    submission.user_name, submission.title
+                );
+                if let Err(error) = bot.send_message(submission.chat_id, thanks).await {
+                    log::warn!(
+                        "{} Submitter in chat id: {} not thanked.",
+                        AppError::from(error),
+                        submission.chat_id
+                    );
+                }
+                format!(
+                    "Approved by {}, added as {}.",
+                    query.from.first_name, voice.clip.file
+                )
+            }
+            // Keep the clip queued with its buttons, so it can be approved again once fixed.
+            Err(error) => {
+                log::error!("{} Submission #{} not added.", error, submission.id);
+                let _guard = Submissions::lock();
+                Submissions::new()?.put_back(submission.clone()).save()?;
+                queued = true;
+                format!(
+                    "Approved by {}, but not added: {}\nIt is still queued.",
+                    query.from.first_name, error
+                )
+            }
+        }
+    } else {
+        format!("Rejected by {}.", query.from.first_name)
+    };
+    if !queued {
+        submission.discard();
+    }
+    log::info!(
+        "Bot reviewed submission #{} from user id: {}: {}",
+        submission.id,
+        submission.user_id,
+        outcome
+    );
+
+    bot.answer_callback_query(&query.id)
+        .text(outcome.as_str())
+        .await?;
+    let caption = format!("{}\n{}", message.caption().unwrap_or_default(), outcome);
+    let mut req = bot
+        .edit_message_caption(message.chat.id, message.id)
+        .caption(caption);
+    if let (true, Some(buttons)) = (queued, message.reply_markup()) {
+        req = req.reply_markup(buttons.clone());
+    }
+    req.await?;
+    Ok(())
+}
+
+// Moves an approved clip into the library, named after its title.
+async fn promote(submission: &Submission) -> Result<Voice, AppError> {
+    let data = submission.read()?;
+    let extension = submission.extension.clone();
+    let mut clip = Clip::new(String::new());
+    clip.title = Some(submission.title.clone());
+    clip.speaker = Some(submission.user_name.clone());
+    tokio::task::spawn_blocking(move || add_clip(&data, &extension, clip))
         .await
-        .expect("AppError::sendVoice");
+        .unwrap_or_else(|error| Err(AppError::Media(error.to_string())))
+}
+
+// Queues a clip from a subscribed group for review and sends it to every owner with
+// Approve and Reject buttons.
+async fn submit(
+    msg: &UpdateWithCx<AutoSend<Bot>, Message>,
+    title: &str,
+) -> Result<String, AppError> {
+    let update = &msg.update;
+    let chat = &update.chat;
+    if !chat.is_group() && !chat.is_supergroup() {
+        return Ok(String::from(
+            "Clips can be submitted from subscribed groups only.",
+        ));
+    }
+    if !matches!(Settings::new()?.get_sub(chat.id), Some(sub) if sub.active) {
+        return Ok(String::from(NOT_SUBSCRIBED));
+    }
+    let user = match update.from().filter(|_| update.sender_chat().is_none()) {
+        Some(user) => user,
+        None => return Ok(String::from("Anonymous senders can't submit clips.")),
+    };
+    let source = match attachment_message(update) {
+        Some(source) if !title.is_empty() => source,
+        _ => {
+            return Ok(String::from(
+                "Usage: reply to a voice message or audio file with /submit <title>",
+            ))
+        }
+    };
+    let owners = owners();
+    if owners.is_empty() {
+        return Ok(String::from("This bot has no owners to review clips."));
+    }
+    let too_many = format!(
+        "You already have {} clips waiting for review, wait until the owners get to them.",
+        MAX_PENDING_PER_USER
+    );
+    // Checked before downloading and again when queueing, another submission may land meanwhile.
+    if Submissions::new()?.pending_from(user.id) >= MAX_PENDING_PER_USER {
+        return Ok(too_many);
+    }
+    let data = match download_attachment(&msg.requester, source).await? {
+        Ok(data) => data,
+        Err(reason) => return Ok(reason),
+    };
+
+    let submission = {
+        let _guard = Submissions::lock();
+        let mut submissions = Submissions::new()?;
+        if submissions.pending_from(user.id) >= MAX_PENDING_PER_USER {
+            return Ok(too_many);
+        }
+        let submission = submissions
+            .add(
+                title,
+                chat.id,
+                user.id,
+                &user.first_name,
+                &data.bytes,
+                &data.extension,
+            )?
+            .clone();
+        submissions.save()?;
+        submission
+    };
+    let caption = format!(
+        "Submission #{}: \"{}\" from {} in {}",
+        submission.id,
+        submission.title,
+        submission.user_name,
+        chat.title().unwrap_or("a group")
+    );
+    let buttons = InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback(
+            String::from("Approve"),
+            format!("{}:{}", APPROVE_CALLBACK, submission.id),
+        ),
+        InlineKeyboardButton::callback(
+            String::from("Reject"),
+            format!("{}:{}", REJECT_CALLBACK, submission.id),
+        ),
+    ]]);
+    for owner in owners {
+        let sent = msg
+            .requester
+            .copy_message(owner, chat.id, source.id)
+            .caption(caption.clone())
+            .reply_markup(buttons.clone())
+            .await;
+        if let Err(error) = sent {
+            log::warn!(
+                "{} Owner id: {} not asked to review submission #{}.",
+                AppError::from(error),
+                owner,
+                submission.id
+            );
+        }
+    }
     log::info!(
-        "Bot successful send voice, chat id: {}, voice: {}.",
+        "Bot queued submission #{} from group id: {}, message sender: {}.",
+        submission.id,
+        chat.id,
+        sender_name(update)
+    );
+    Ok(format!(
+        "Thanks! \"{}\" is waiting for review by the bot owners.",
+        submission.title
+    ))
+}
+
+// The message itself when it carries the clip, otherwise the message it replies to.
+fn attachment_message(msg: &Message) -> Option<&Message> {
+    let has_clip = |m: &Message| {
+        m.voice().is_some()
+            || m.audio().is_some()
+            || matches!(
+                m.document().and_then(|d| d.mime_type.as_ref()),
+                Some(mime) if mime.essence_str().starts_with("audio/")
+            )
+    };
+    if has_clip(msg) {
+        return Some(msg);
+    }
+    msg.reply_to_message().filter(|reply| has_clip(reply))
+}
+
+// Downloads the voice or audio file the owner sent and adds it to the library. The preview is
+// the confirmation, so nothing is returned on success.
+async fn add_voice(
+    msg: &UpdateWithCx<AutoSend<Bot>, Message>,
+    title: &str,
+) -> Result<Option<String>, AppError> {
+    let update = &msg.update;
+    if !update.chat.is_private() {
+        return Ok(Some(String::from(
+            "Send new clips to me in a private chat.",
+        )));
+    }
+    let owner = update.from().filter(|user| is_owner(user.id));
+    let owner = match owner {
+        Some(owner) => owner,
+        None => {
+            log::info!(
+                "Bot rejected /addvoice from non-owner: {}.",
+                sender_name(update)
+            );
+            return Ok(Some(String::from(
+                "Only the owners of this bot can add clips.",
+            )));
+        }
+    };
+    if title.is_empty() {
+        return Ok(Some(String::from(
+            "Usage: send a voice message or audio file with the caption /addvoice <title>",
+        )));
+    }
+    let data = match download_attachment(&msg.requester, update).await? {
+        Ok(data) => data,
+        Err(reason) => return Ok(Some(reason)),
+    };
+
+    let mut clip = Clip::new(String::new());
+    clip.title = Some(String::from(title));
+    clip.speaker = Some(owner.first_name.clone());
+    // Converting may run ffmpeg, keep it off the async workers.
+    let added = tokio::task::spawn_blocking(move || add_clip(&data.bytes, &data.extension, clip))
+        .await
+        .unwrap_or_else(|error| Err(AppError::Media(error.to_string())));
+    let voice = match added {
+        Ok(voice) => voice,
+        Err(AppError::Media(reason)) => return Ok(Some(format!("Not added: {}.", reason))),
+        Err(error) => return Err(error),
+    };
+    log::info!(
+        "Bot added voice: {} from owner: {}, user id: {}.",
+        voice.clip.file,
+        owner.first_name,
+        owner.id
+    );
+    let caption = format!("Added to the library: {} ({})", title, voice.clip.file);
+    send_voice(&msg.requester, update.chat.id, &voice, Some(caption)).await?;
+    Ok(None)
+}
+
+// Bytes and file extension of an uploaded clip.
+struct Attachment {
+    bytes: Vec<u8>,
+    extension: String,
+}
+
+// Voice messages, audio files and documents with an audio MIME type are accepted. The inner
+// error is the reply for the sender.
+async fn download_attachment(
+    bot: &AutoSend<Bot>,
+    msg: &Message,
+) -> Result<Result<Attachment, String>, AppError> {
+    let (file_id, size) = if let Some(voice) = msg.voice() {
+        (&voice.file_id, voice.file_size.unwrap_or_default())
+    } else if let Some(audio) = msg.audio() {
+        (&audio.file_id, audio.file_size.unwrap_or_default().into())
+    } else if let Some(document) = msg.document().filter(|document| {
+        matches!(&document.mime_type, Some(mime) if mime.essence_str().starts_with("audio/"))
+    }) {
+        (
+            &document.file_id,
+            document.file_size.unwrap_or_default().into(),
+        )
+    } else {
+        return Ok(Err(String::from(
+            "Attach a voice message or an audio file.",
+        )));
+    };
+    if size > MAX_DOWNLOAD_SIZE {
+        return Ok(Err(String::from(
+            "Telegram lets bots download files of up to 20 MB only.",
+        )));
+    }
+
+    let file = bot.get_file(file_id).await?;
+    let mut bytes = Vec::with_capacity(file.file_size as usize);
+    bot.download_file(&file.file_path, &mut bytes).await?;
+    let extension = Path::new(&file.file_path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_else(|| String::from("ogg"));
+    Ok(Ok(Attachment { bytes, extension }))
+}
+
+// Packs are separated by spaces or commas, "all" goes back to every clip.
+fn set_packs(chat_id: i64, arg: &str, scheduler: &SchedulerHandle) -> Result<String, AppError> {
+    let library = catalog();
+    let available = library.packs();
+    let _guard = Settings::lock();
+    let mut set = Settings::new()?;
+    let sub = match set.get_sub_mut(chat_id) {
+        Some(sub) => sub,
+        None => return Ok(String::from(NOT_SUBSCRIBED)),
+    };
+    if arg.is_empty() {
+        let lines: Vec<String> = available
+            .iter()
+            .map(|(pack, clips)| format!("{} ({} clips)", pack, clips))
+            .collect();
+        return Ok(format!(
+            "Voice packs of this group: {}.\nAvailable: {}\nUsage: /packs <pack> [<pack>...] | all",
+            pack_list(&sub.packs),
+            if lines.is_empty() {
+                String::from("none")
+            } else {
+                lines.join(", ")
+            }
+        ));
+    }
+
+    let mut packs: Vec<String> = vec![];
+    for pack in arg.split(|c: char| c == ',' || c.is_whitespace()) {
+        let pack = pack.to_lowercase();
+        if pack.is_empty() || packs.contains(&pack) {
+            continue;
+        }
+        if pack == "all" {
+            packs.clear();
+            break;
+        }
+        if !available.contains_key(&pack) {
+            return Ok(format!(
+                "Unknown voice pack '{}', send /packs to list them.",
+                pack
+            ));
+        }
+        packs.push(pack);
+    }
+
+    let reply = format!("Voice packs of this group are now: {}.", pack_list(&packs));
+    log::info!(
+        "Bot set voice packs of group id: {} to {}.",
+        chat_id,
+        pack_list(&packs)
+    );
+    sub.update_packs(packs);
+    set.save()?;
+    scheduler.reload();
+    Ok(reply)
+}
+
+// Kinds are separated by spaces or commas, "any" allows all of them and "off" none.
+fn set_extra(chat_id: i64, arg: &str, scheduler: &SchedulerHandle) -> Result<String, AppError> {
+    let _guard = Settings::lock();
+    let mut set = Settings::new()?;
+    let sub = match set.get_sub_mut(chat_id) {
+        Some(sub) => sub,
+        None => return Ok(String::from(NOT_SUBSCRIBED)),
+    };
+    if arg.is_empty() {
+        return Ok(format!(
+            "Extras sent with the reminders of this group: {}.\nUsage: /extra off | any | sticker gif photo",
+            extra_list(&sub.extras)
+        ));
+    }
+
+    let mut kinds: Vec<Format> = vec![];
+    for word in arg.split(|c: char| c == ',' || c.is_whitespace()) {
+        let word = word.to_ascii_lowercase();
+        let add: &[Format] = match word.as_str() {
+            "" => continue,
+            "off" | "none" => {
+                kinds.clear();
+                break;
+            }
+            "any" | "all" => &[Format::Sticker, Format::Animation, Format::Photo],
+            "sticker" | "stickers" => &[Format::Sticker],
+            "gif" | "gifs" | "animation" => &[Format::Animation],
+            "photo" | "photos" | "image" => &[Format::Photo],
+            _ => {
+                return Ok(format!(
+                    "Unknown extra '{}', choose from off, any, sticker, gif and photo.",
+                    word
+                ))
+            }
+        };
+        for kind in add {
+            if !kinds.contains(kind) {
+                kinds.push(*kind);
+            }
+        }
+    }
+
+    let reply = format!(
+        "Extras sent with the reminders of this group are now: {}.",
+        extra_list(&kinds)
+    );
+    log::info!(
+        "Bot set extras of group id: {} to {}.",
+        chat_id,
+        extra_list(&kinds)
+    );
+    sub.update_extras(kinds);
+    set.save()?;
+    scheduler.reload();
+    Ok(reply)
+}
+
+fn extra_list(kinds: &[Format]) -> String {
+    if kinds.is_empty() {
+        return String::from("off");
+    }
+    let names: Vec<&str> = kinds
+        .iter()
+        .map(|kind| match kind {
+            Format::Sticker => "sticker",
+            Format::Animation => "gif",
+            Format::Photo => "photo",
+            Format::Voice => "voice",
+            Format::Audio => "audio",
+        })
+        .collect();
+    names.join(", ")
+}
+
+fn pack_list(packs: &[String]) -> String {
+    if packs.is_empty() {
+        String::from("all")
+    } else {
+        packs.join(", ")
+    }
+}
+
+// Next reminder of the subscription, formatted in its own timezone.
+fn next_reminder(sub: &Subscribe) -> Option<String> {
+    let zone = sub.zone().ok()?;
+    let next = sub.schedule().ok()?.next_after(&zone, Utc::now())?;
+    Some(format!(
+        "{} ({})",
+        zone.local(&next).format("%Y-%m-%d %H:%M"),
+        zone
+    ))
+}
+
+/// What reached the chat. A chat that refuses voice notes may still take its extra.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sent {
+    Voice,
+    ExtraOnly,
+}
+
+/// Sends a reminder to the chat, drawn from the voice packs and shuffle bag of its subscription
+/// when it has one. The caller waits on the throttle before the voice, the extra waits itself.
+pub(crate) async fn voice_handler(
+    bot: &AutoSend<Bot>,
+    throttle: &Throttle,
+    chat_id: i64,
+    sub: Option<&Subscribe>,
+    caption: Option<String>,
+) -> Result<Sent, AppError> {
+    let library = catalog();
+    let (selected, voice) = choose_for_chat(&library.voices, chat_id, sub)?;
+    let caption = caption.or_else(|| voice.clip.caption.clone());
+    let sent = send_voice(bot, chat_id, voice, caption).await;
+    // Only a clip that went out leaves the bag, a failed send may draw it again.
+    if sent.is_ok() {
+        if sub.is_some() {
+            mark_played(selected, ShuffleBags::lock().played_mut(chat_id), voice);
+            save_in_background();
+        }
+        log::info!(
+            "Bot successful send voice, chat id: {}, voice: {}.",
+            chat_id,
+            voice.key()
+        );
+    }
+    // A chat may allow stickers or photos but not voices, so the extra still goes out when the
+    // voice is refused. Failures the scheduler retries would send it twice.
+    let refused = matches!(&sent, Err(error) if error.send_failure() == SendFailure::Permanent);
+    let extra = match (sent.is_ok() || refused, sub) {
+        (true, Some(sub)) => send_extra(bot, throttle, chat_id, &sub.extras).await,
+        _ => Ok(false),
+    };
+    // A failed extra must not make the scheduler send the voice again.
+    if let Err(error) = &extra {
+        log::warn!("{} Extra of chat id: {} not sent.", error, chat_id);
+    }
+    match sent {
+        Ok(()) => Ok(Sent::Voice),
+        // Counted as delivered, or every catch-up would send the extra once more.
+        Err(error) if matches!(extra, Ok(true)) => {
+            log::warn!(
+                "{} Voice refused by chat id: {}, only the extra was sent.",
+                error,
+                chat_id
+            );
+            Ok(Sent::ExtraOnly)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// Sticker, GIF or photo the chat wants along with its reminder, if any. Returns whether one
+// went out.
+async fn send_extra(
+    bot: &AutoSend<Bot>,
+    throttle: &Throttle,
+    chat_id: i64,
+    kinds: &[Format],
+) -> Result<bool, AppError> {
+    let extra = match choose_extra(kinds) {
+        Some(extra) => extra,
+        None => return Ok(false),
+    };
+    throttle.acquire(chat_id).await;
+    let mut sent = send_voice(bot, chat_id, &extra, extra.clip.caption.clone()).await;
+    // Flood control holds back every send, the extra tries once more when it has passed.
+    if let Err(error) = &sent {
+        if let SendFailure::Transient {
+            retry_after: Some(retry_after),
+        } = error.send_failure()
+        {
+            throttle.pause(retry_after);
+            throttle.acquire(chat_id).await;
+            sent = send_voice(bot, chat_id, &extra, extra.clip.caption.clone()).await;
+        }
+    }
+    sent?;
+    log::info!(
+        "Bot successful send extra, chat id: {}, extra: {}.",
         chat_id,
-        voice_clone.file_name().unwrap().to_str().unwrap()
+        extra.key()
     );
-    teloxide::respond(())
+    Ok(true)
+}
+
+// Draws from the chat's shuffle bag, chats without a subscription get a plain random clip.
+// Returns the clips drawn from along with the chosen one.
+fn choose_for_chat<'a>(
+    voices: &'a [Voice],
+    chat_id: i64,
+    sub: Option<&Subscribe>,
+) -> Result<(Vec<&'a Voice>, &'a Voice), AppError> {
+    let all: Vec<&Voice> = voices.iter().collect();
+    let sub = match sub {
+        Some(sub) => sub,
+        None => return Ok((all.clone(), choose_voice(all, &[])?)),
+    };
+    // A pack that vanished from the library must not silence the chat, use every clip then.
+    let mut selected: Vec<&Voice> = voices
+        .iter()
+        .filter(|voice| sub.packs.is_empty() || voice.in_any_pack(&sub.packs))
+        .collect();
+    if selected.is_empty() {
+        log::warn!(
+            "AppError::media: no clip in voice packs {} of chat id: {}, all clips used.",
+            pack_list(&sub.packs),
+            chat_id
+        );
+        selected = all;
+    }
+    let voice = choose_voice(
+        selected.iter().copied(),
+        ShuffleBags::lock().played(chat_id),
+    )?;
+    Ok((selected, voice))
+}
+
+// Sends the clip by its cached file_id, uploading it only the first time or when Telegram no
+// longer knows the id. Manifest entries with a file_id are never uploaded.
+async fn send_voice(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    voice: &Voice,
+    caption: Option<String>,
+) -> Result<(), AppError> {
+    if let Some(file_id) = &voice.clip.file_id {
+        let file = InputFile::file_id(file_id.clone());
+        send_file(bot, chat_id, voice.format, file, caption).await?;
+        return Ok(());
+    }
+    let path = voice.path.as_path();
+    let hash = voice.hash.clone();
+    let cached = hash.as_deref().and_then(|hash| {
+        let file_ids = FILE_IDS.lock().unwrap_or_else(|e| e.into_inner());
+        file_ids.get(hash).cloned()
+    });
+
+    if let (Some(hash), Some(file_id)) = (hash.as_deref(), cached) {
+        let file = InputFile::file_id(file_id);
+        match send_file(bot, chat_id, voice.format, file, caption.clone()).await {
+            Ok(_) => return Ok(()),
+            Err(error) if error.is_bad_file_id() => {
+                log::warn!("{} Voice: {} is uploaded again.", error, path.display());
+                let mut file_ids = FILE_IDS.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(error) = file_ids.forget(hash).save() {
+                    log::error!("{} Cached file id not removed.", error);
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    let file = InputFile::file(path.to_path_buf());
+    let message = send_file(bot, chat_id, voice.format, file, caption).await?;
+    let file_id = match voice.format {
+        Format::Voice => message.voice().map(|sent| sent.file_id.clone()),
+        Format::Audio => message.audio().map(|sent| sent.file_id.clone()),
+        Format::Sticker => message.sticker().map(|sent| sent.file_id.clone()),
+        Format::Animation => message.animation().map(|sent| sent.file_id.clone()),
+        Format::Photo => message
+            .photo()
+            .and_then(|sizes| sizes.last())
+            .map(|sent| sent.file_id.clone()),
+    };
+    if let (Some(hash), Some(file_id)) = (hash, file_id) {
+        let mut file_ids = FILE_IDS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(error) = file_ids.insert(hash, file_id).save() {
+            log::error!("{} File id of voice: {} not cached.", error, path.display());
+        }
+    }
+    Ok(())
+}
+
+// Clips that could not be converted to OGG/Opus go out as music files instead of voice notes.
+// Stickers take no caption.
+async fn send_file(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    format: Format,
+    file: InputFile,
+    caption: Option<String>,
+) -> Result<Message, AppError> {
+    let message = match format {
+        Format::Voice => {
+            let mut req = bot.send_voice(chat_id, file);
+            if let Some(caption) = caption {
+                req = req.caption(caption);
+            }
+            req.await?
+        }
+        Format::Audio => {
+            let mut req = bot.send_audio(chat_id, file);
+            if let Some(caption) = caption {
+                req = req.caption(caption);
+            }
+            req.await?
+        }
+        Format::Sticker => bot.send_sticker(chat_id, file).await?,
+        Format::Animation => {
+            let mut req = bot.send_animation(chat_id, file);
+            if let Some(caption) = caption {
+                req = req.caption(caption);
+            }
+            req.await?
+        }
+        Format::Photo => {
+            let mut req = bot.send_photo(chat_id, file);
+            if let Some(caption) = caption {
+                req = req.caption(caption);
+            }
+            req.await?
+        }
+    };
+    Ok(message)
 }
@@ -0,0 +1,109 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{error::AppError, utils::*};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::Read, path::Path};
+
+// Deliveries older than this are dropped when the ledger is saved.
+const RETENTION_DAYS: i64 = 30;
+
+/// One reminder that was sent, keyed by chat and the scheduled slot it belongs to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub chat_id: i64,
+    pub slot: DateTime<Utc>,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Persisted record of sent reminders, used to skip duplicates and find slots missed during
+/// downtime.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    #[serde(default)]
+    deliveries: Vec<Delivery>,
+    #[serde(skip)]
+    file_path: String,
+}
+
+impl Ledger {
+    pub fn new() -> Result<Self, AppError> {
+        let l = Self::make()?;
+        l.save()?;
+        Ok(l)
+    }
+    pub(crate) fn save(&self) -> Result<(), AppError> {
+        write_atomic(Path::new(&self.file_path), &serde_json::to_vec(&self)?)
+    }
+    pub(crate) fn is_delivered(&self, chat_id: i64, slot: DateTime<Utc>) -> bool {
+        self.deliveries
+            .iter()
+            .any(|d| d.chat_id == chat_id && d.slot == slot)
+    }
+    pub(crate) fn record(
+        &mut self,
+        chat_id: i64,
+        slot: DateTime<Utc>,
+        sent_at: DateTime<Utc>,
+    ) -> &Self {
+        if !self.is_delivered(chat_id, slot) {
+            self.deliveries.push(Delivery {
+                chat_id,
+                slot,
+                sent_at,
+            });
+        }
+        let oldest = Utc::now() - Duration::days(RETENTION_DAYS);
+        self.deliveries.retain(|d| d.slot > oldest);
+        self
+    }
+    pub(crate) fn migrate(&mut self, from: i64, to: i64) -> &Self {
+        self.deliveries
+            .iter_mut()
+            .filter(|d| d.chat_id == from)
+            .for_each(|d| d.chat_id = to);
+        self
+    }
+    pub(crate) fn last_slot(&self, chat_id: i64) -> Option<DateTime<Utc>> {
+        self.deliveries
+            .iter()
+            .filter(|d| d.chat_id == chat_id)
+            .map(|d| d.slot)
+            .max()
+    }
+    fn make() -> Result<Self, AppError> {
+        let name = "Ledger.json";
+        let dirs = get_setting_dir()?;
+        let dir = dirs.data_dir();
+        let path = format!("{}/{}", dir.display(), name);
+        let is_exists = check_exists_and_create(Path::new(&path), b"{}")?;
+
+        let mut ledger = if is_exists {
+            let mut f = File::open(&path)?;
+            let mut buffer = String::new();
+            f.read_to_string(&mut buffer)?;
+            serde_json::from_str::<Ledger>(&buffer)?
+        } else {
+            Ledger {
+                deliveries: vec![],
+                file_path: String::new(),
+            }
+        };
+        ledger.file_path = path;
+        Ok(ledger)
+    }
+}
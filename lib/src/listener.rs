@@ -14,7 +14,7 @@
  * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 
-use crate::settings::Settings;
+use crate::scheduler::SchedulerHandle;
 use futures::{
     future::{ready, Either},
     stream::{self, Stream, StreamExt},
@@ -27,50 +27,9 @@ use teloxide::{
     },
     payloads::GetUpdates,
     requests::{HasPayload, Request, Requester},
-    types::{
-        AllowedUpdate, Chat, ChatKind, ChatPublic, ForwardKind, ForwardOrigin, MediaKind,
-        MediaText, Message, MessageCommon, MessageKind, PublicChatGroup, PublicChatKind,
-        SemiparsedVec, Update, UpdateKind,
-    },
+    types::{AllowedUpdate, SemiparsedVec, Update},
 };
 
-fn mock_update_chat(chat_id: i64) -> Update {
-    Update {
-        id: 0,
-        kind: UpdateKind::Message(Message {
-            id: 0,
-            date: 0,
-            chat: Chat {
-                id: chat_id,
-                kind: ChatKind::Public(ChatPublic {
-                    title: None,
-                    kind: PublicChatKind::Group(PublicChatGroup { permissions: None }),
-                    description: None,
-                    invite_link: None,
-                }),
-                photo: None,
-                pinned_message: None,
-                message_auto_delete_time: None,
-            },
-            via_bot: None,
-            kind: MessageKind::Common(MessageCommon {
-                from: None,
-                sender_chat: None,
-                author_signature: None,
-                forward_kind: ForwardKind::Origin(ForwardOrigin {
-                    reply_to_message: None,
-                }),
-                edit_date: None,
-                media_kind: MediaKind::Text(MediaText {
-                    text: "".to_string(),
-                    entities: vec![],
-                }),
-                reply_markup: None,
-            }),
-        }),
-    }
-}
-
 struct State<B: Requester> {
     bot: B,
     timeout: Option<u32>,
@@ -79,6 +38,8 @@ struct State<B: Requester> {
     offset: i32,
     flag: AsyncStopFlag,
     token: AsyncStopToken,
+    scheduler: SchedulerHandle,
+    offline: bool,
 }
 
 fn stream<B>(st: &mut State<B>) -> impl Stream<Item = Result<Update, B::Err>> + '_
@@ -93,6 +54,8 @@ where
             bot,
             offset,
             flag,
+            scheduler,
+            offline,
             ..
         } = &mut *state;
 
@@ -121,8 +84,21 @@ where
         };
 
         let updates = match req.send().await {
-            Err(err) => return Some((Either::Left(stream::once(ready(Err(err)))), state)),
+            Err(err) => {
+                if !*offline {
+                    log::warn!("Bot lost connection to Telegram, details: {}", err);
+                    *offline = true;
+                }
+                return Some((Either::Left(stream::once(ready(Err(err)))), state));
+            }
             Ok(SemiparsedVec(updates)) => {
+                // Reminders due during the outage could not be sent, let the scheduler catch up.
+                if *offline {
+                    log::info!("Bot reconnected to Telegram.");
+                    *offline = false;
+                    scheduler.catch_up();
+                }
+
                 // Set offset to the last update's id + 1
                 if let Some(upd) = updates.last() {
                     let id: i32 = match upd {
@@ -153,16 +129,7 @@ where
             }
         };
 
-        let set = Settings::new();
-        let subs = set.subscribe;
-        let sub_updates = subs
-            .into_iter()
-            .map(|sub| mock_update_chat(sub.chat_id))
-            .map(Ok);
-
-        let all_updates = updates.chain(sub_updates);
-
-        Some((Either::Right(stream::iter(all_updates)), state))
+        Some((Either::Right(stream::iter(updates)), state))
     })
     .flatten()
 }
@@ -172,6 +139,7 @@ fn polling<R>(
     timeout: Option<Duration>,
     limit: Option<u8>,
     allowed_updates: Option<Vec<AllowedUpdate>>,
+    scheduler: SchedulerHandle,
 ) -> impl UpdateListener<R::Err>
 where
     R: Requester + 'static,
@@ -186,17 +154,29 @@ where
         offset: 0,
         flag,
         token,
+        scheduler,
+        offline: false,
     };
     let stop_token = |st: &mut State<_>| st.token.clone();
 
     StatefulListener::new(state, stream, stop_token)
 }
 
-pub fn polling_listener<R>(requester: R) -> impl UpdateListener<R::Err>
+pub fn polling_listener<R>(requester: R, scheduler: SchedulerHandle) -> impl UpdateListener<R::Err>
 where
     R: Requester + 'static,
     <R as Requester>::GetUpdatesFaultTolerant: Send,
 {
     // delete_webhook_if_setup(&requester).await;
-    polling(requester, Some(Duration::from_secs(10)), None, None)
+    polling(
+        requester,
+        Some(Duration::from_secs(10)),
+        None,
+        Some(vec![
+            AllowedUpdate::Message,
+            AllowedUpdate::MyChatMember,
+            AllowedUpdate::CallbackQuery,
+        ]),
+        scheduler,
+    )
 }
@@ -14,46 +14,921 @@
  * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 
-use rand::{rngs::ThreadRng, seq::IteratorRandom};
+use crate::{
+    error::AppError,
+    extras::{get_extra_dir, EXTRAS},
+    file_ids::content_hash,
+    utils::{get_setting_dir, write_atomic},
+};
+use once_cell::sync::Lazy;
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, HashSet},
     env,
-    fs::{read_dir, ReadDir},
-    path::PathBuf,
+    fs::{create_dir_all, metadata, read_dir, read_to_string, remove_file, rename, write, File},
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
-#[allow(dead_code)]
-pub(crate) fn get_voice_dir() -> ReadDir {
-    let dir_path = env::var("BOT_VOICE_DIR");
-    let dir_path = match dir_path {
-        Ok(path) => path,
-        Err(error) => {
-            log::error!("AppError::env: BOT_VOICE_DIR not set!");
-            panic!("AppError::env: BOT_VOICE_DIR not set! Details: {:?}", error);
+pub(crate) const MANIFEST_NAME: &str = "manifest.json";
+// Telegram accepts voice notes of up to 50 MB from bots.
+const MAX_VOICE_SIZE: u64 = 50 * 1024 * 1024;
+// Extensions handed to ffmpeg, and those Telegram can still play as music when that fails.
+const TRANSCODABLE: [&str; 9] = [
+    "mp3", "m4a", "wav", "aac", "flac", "ogg", "oga", "opus", "webm",
+];
+const SENDABLE_AUDIO: [&str; 2] = ["mp3", "m4a"];
+// How often the voice and extras directories are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Clips are added one at a time, each rewrites the manifest.
+static ADD_CLIP_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Metadata of one clip in the voice manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Clip {
+    /// File name relative to the voice directory.
+    pub file: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub speaker: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Relative chance of being picked, 0 never picks the clip.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Sent with the voice unless the reminder brings its own caption.
+    #[serde(default)]
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Already uploaded file to send instead of `file`, needs `kind`.
+    #[serde(default)]
+    pub file_id: Option<String>,
+    #[serde(default)]
+    pub kind: Option<Format>,
+    /// Voice packs the clip belongs to, besides the subdirectory it lives in.
+    #[serde(default)]
+    pub packs: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Clip {
+    pub(crate) fn new(file: String) -> Self {
+        Clip {
+            file,
+            title: None,
+            speaker: None,
+            language: None,
+            weight: default_weight(),
+            caption: None,
+            tags: vec![],
+            file_id: None,
+            kind: None,
+            packs: vec![],
+            enabled: true,
+        }
+    }
+}
+
+/// The voice manifest, `manifest.json` in the voice directory unless `BOT_VOICE_MANIFEST`
+/// points elsewhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Also use files of the voice directory that the manifest does not list.
+    #[serde(default = "default_auto_discover")]
+    pub auto_discover: bool,
+    #[serde(default)]
+    pub clips: Vec<Clip>,
+}
+
+fn default_auto_discover() -> bool {
+    true
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            auto_discover: default_auto_discover(),
+            clips: vec![],
+        }
+    }
+}
+
+impl Voice {
+    /// Voice packs of the clip: its top level subdirectory and the packs listed in the manifest.
+    pub fn packs(&self) -> Vec<&str> {
+        let mut packs: Vec<&str> = self.clip.packs.iter().map(String::as_str).collect();
+        if let Some((dir, _)) = self.clip.file.split_once('/') {
+            if !packs.contains(&dir) {
+                packs.push(dir);
+            }
+        }
+        packs
+    }
+    /// Identifies the clip in shuffle bags, entries sharing a placeholder `file` differ in
+    /// their `file_id`.
+    pub fn key(&self) -> &str {
+        self.clip.file_id.as_deref().unwrap_or(&self.clip.file)
+    }
+    /// Pack names are compared ignoring case, "Cantonese/" and "cantonese" are one pack.
+    pub fn in_any_pack(&self, packs: &[String]) -> bool {
+        self.packs().iter().any(|pack| {
+            packs
+                .iter()
+                .any(|p| p.to_lowercase() == pack.to_lowercase())
+        })
+    }
+}
+
+impl Library {
+    /// Names of all voice packs, in lower case, with the number of clips in each.
+    pub fn packs(&self) -> BTreeMap<String, usize> {
+        let mut packs = BTreeMap::new();
+        for voice in &self.voices {
+            let mut names: Vec<String> = voice.packs().iter().map(|p| p.to_lowercase()).collect();
+            names.sort();
+            names.dedup();
+            for pack in names {
+                *packs.entry(pack).or_insert(0) += 1;
+            }
+        }
+        packs
+    }
+}
+
+/// How a clip or extra is sent to Telegram, the `kind` of `file_id` manifest entries.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// OGG/Opus, shown as a voice note.
+    Voice,
+    /// MP3 or M4A that could not be converted, sent as a music file.
+    Audio,
+    Sticker,
+    /// GIF or silent MP4.
+    Animation,
+    Photo,
+}
+
+/// A clip on disk together with its manifest entry. `path` is the file that is sent, the
+/// converted copy for clips that were not OGG/Opus, and empty for `file_id` entries. `hash` is
+/// the content hash of `path` taken when the library is scanned, used to cache its `file_id`.
+#[derive(Debug, Clone)]
+pub struct Voice {
+    pub path: PathBuf,
+    pub format: Format,
+    pub clip: Clip,
+    pub hash: Option<String>,
+}
+
+pub(crate) fn get_voice_dir() -> Result<PathBuf, AppError> {
+    let dir_path = env::var("BOT_VOICE_DIR")
+        .map_err(|_| AppError::Config(String::from("BOT_VOICE_DIR not set!")))?;
+    Ok(PathBuf::from(dir_path))
+}
+
+pub(crate) fn get_manifest_path(dir: &Path) -> PathBuf {
+    match env::var("BOT_VOICE_MANIFEST") {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => dir.join(MANIFEST_NAME),
+    }
+}
+
+// A missing manifest means every file of the directory is a clip.
+fn read_manifest(path: &Path) -> Result<Manifest, AppError> {
+    if !path.exists() {
+        return Ok(Manifest::default());
+    }
+    let buffer = read_to_string(path)?;
+    serde_json::from_str::<Manifest>(&buffer).map_err(|error| {
+        AppError::Media(format!("manifest {} is invalid: {}", path.display(), error))
+    })
+}
+
+/// A file of the voice directory that cannot be sent as a voice note.
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Usable clips of the voice directory and the files that were turned down.
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    pub manifest: Manifest,
+    pub voices: Vec<Voice>,
+    pub rejected: Vec<Rejected>,
+}
+
+/// A directory of media scanned into a [`Library`]: the voice clips, or the extras sent along
+/// with them. Both share the manifest format, validation report and watcher.
+pub(crate) struct Source {
+    pub(crate) name: &'static str,
+    pub(crate) dir: fn() -> Result<PathBuf, AppError>,
+    pub(crate) manifest: fn(&Path) -> PathBuf,
+    pub(crate) recursive: fn() -> bool,
+    /// Turns a file into what is sent and how, or the reason it cannot be sent.
+    pub(crate) ingest: fn(&Path) -> Result<(PathBuf, Format), String>,
+    /// Kinds the library may hold, `file_id` entries of any other kind are rejected.
+    pub(crate) formats: &'static [Format],
+    pub(crate) catalog: &'static Lazy<RwLock<Arc<Library>>>,
+}
+
+// Path, size and modification time of each file, compared to notice changes.
+type Fingerprint = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+pub(crate) static VOICES: Source = Source {
+    name: "Voice library",
+    dir: get_voice_dir,
+    manifest: get_manifest_path,
+    recursive: is_recursive,
+    ingest,
+    formats: &[Format::Voice, Format::Audio],
+    catalog: &CATALOG,
+};
+
+// Current voice library, replaced as a whole so a send never sees a half scanned directory.
+// Filled by `check_voice_library` at startup, which scans the directory once.
+static CATALOG: Lazy<RwLock<Arc<Library>>> =
+    Lazy::new(|| RwLock::new(Arc::new(Library::default())));
+
+/// Snapshot of the voice library, kept valid even if the directory changes meanwhile.
+pub(crate) fn catalog() -> Arc<Library> {
+    VOICES.snapshot()
+}
+
+// Subdirectories of the voice directory are scanned too when set to "true" or "1".
+fn is_recursive() -> bool {
+    matches!(
+        env::var("BOT_VOICE_RECURSIVE").as_deref(),
+        Ok("true") | Ok("1")
+    )
+}
+
+impl Source {
+    pub(crate) fn snapshot(&self) -> Arc<Library> {
+        self.catalog
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn replace(&self, library: Library) {
+        *self.catalog.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(library);
+    }
+
+    /// Enabled entries of the manifest, followed by unlisted files of the directory when auto
+    /// discovery is on. Entries may name a Telegram `file_id` and its `kind` instead of a file.
+    /// Anything that cannot be sent is rejected.
+    pub(crate) fn scan(&self, dir: &Path) -> Result<Library, AppError> {
+        let manifest_path = (self.manifest)(dir);
+        let manifest = read_manifest(&manifest_path)?;
+        let listed: HashSet<&str> = manifest.clips.iter().map(|c| c.file.as_str()).collect();
+
+        let mut library = Library {
+            manifest: manifest.clone(),
+            ..Library::default()
+        };
+        let add = |library: &mut Library, path: PathBuf, clip: Clip| match (self.ingest)(&path) {
+            Ok((sent, format)) => library.voices.push(Voice {
+                hash: hash_of(&sent),
+                path: sent,
+                format,
+                clip,
+            }),
+            Err(reason) => library.rejected.push(Rejected { path, reason }),
+        };
+        for clip in manifest.clips.iter().filter(|c| c.enabled) {
+            match (&clip.file_id, clip.kind) {
+                (Some(file_id), Some(format)) if !self.formats.contains(&format) => {
+                    library.rejected.push(Rejected {
+                        path: PathBuf::from(file_id),
+                        reason: format!("{:?} does not belong in the {}", format, self.name),
+                    })
+                }
+                (Some(_), Some(format)) => library.voices.push(Voice {
+                    path: PathBuf::new(),
+                    format,
+                    clip: clip.clone(),
+                    hash: None,
+                }),
+                (Some(file_id), None) => library.rejected.push(Rejected {
+                    path: PathBuf::from(file_id),
+                    reason: String::from("file_id entries need a kind"),
+                }),
+                (None, _) => add(&mut library, dir.join(&clip.file), clip.clone()),
+            }
+        }
+
+        if manifest.auto_discover {
+            let mut files = vec![];
+            let mut skipped = vec![];
+            collect_files(dir, (self.recursive)(), &mut files, &mut skipped)?;
+            for path in files {
+                if same_file(&path, &manifest_path) {
+                    continue;
+                }
+                let file = relative_name(dir, &path);
+                if !listed.contains(file.as_str()) {
+                    add(&mut library, path, Clip::new(file));
+                }
+            }
+            library.rejected.extend(skipped);
+        }
+        Ok(library)
+    }
+
+    fn report(&self, library: &Library) {
+        for rejected in &library.rejected {
+            log::warn!(
+                "AppError::media: {} rejected, {}.",
+                rejected.path.display(),
+                rejected.reason
+            );
+        }
+        log::info!(
+            "{}: {} usable, {} file(s) rejected.",
+            self.name,
+            library.voices.len(),
+            library.rejected.len()
+        );
+    }
+
+    // Polls modification times, which works on every platform and network share.
+    fn spawn_watcher(&'static self) {
+        tokio::spawn(async move {
+            let mut seen = match (self.dir)() {
+                Ok(dir) => self.fingerprint_blocking(dir).await.ok(),
+                Err(_) => None,
+            };
+            loop {
+                tokio::time::sleep(WATCH_INTERVAL).await;
+                let dir = match (self.dir)() {
+                    Ok(dir) => dir,
+                    Err(error) => {
+                        log::error!("{} {} not watched.", error, self.name);
+                        return;
+                    }
+                };
+                let current = match self.fingerprint_blocking(dir.clone()).await {
+                    Ok(current) => current,
+                    Err(error) => {
+                        log::error!("{} {} not readable.", error, self.name);
+                        continue;
+                    }
+                };
+                if seen.as_ref() == Some(&current) {
+                    continue;
+                }
+                seen = Some(current);
+                // New clips may need converting, keep it off the async workers.
+                let scanned = tokio::task::spawn_blocking(move || self.scan(&dir))
+                    .await
+                    .unwrap_or_else(|error| Err(AppError::Media(error.to_string())));
+                match scanned {
+                    // An emptied directory is most likely mid-copy, keep sending the old files.
+                    Ok(library) if library.voices.is_empty() => {
+                        self.report(&library);
+                        log::warn!("{} has nothing usable, previous one kept.", self.name);
+                    }
+                    Ok(library) => {
+                        log::info!("{} changed, reloaded.", self.name);
+                        self.report(&library);
+                        self.replace(library);
+                    }
+                    Err(error) => log::error!("{} Previous {} kept.", error, self.name),
+                }
+            }
+        });
+    }
+
+    // Walking a large directory blocks, keep it off the async workers.
+    async fn fingerprint_blocking(&'static self, dir: PathBuf) -> Result<Fingerprint, AppError> {
+        tokio::task::spawn_blocking(move || self.fingerprint(&dir))
+            .await
+            .unwrap_or_else(|error| Err(AppError::Media(error.to_string())))
+    }
+
+    // Path, size and modification time of every file the library is built from.
+    fn fingerprint(&self, dir: &Path) -> Result<Fingerprint, AppError> {
+        let mut files = vec![];
+        collect_files(dir, (self.recursive)(), &mut files, &mut vec![])?;
+        let manifest_path = (self.manifest)(dir);
+        if !files.iter().any(|path| same_file(path, &manifest_path)) && manifest_path.is_file() {
+            files.push(manifest_path);
+        }
+        Ok(files
+            .into_iter()
+            .map(|path| {
+                let meta = metadata(&path).ok();
+                let len = meta.as_ref().map_or(0, |meta| meta.len());
+                let modified = meta.and_then(|meta| meta.modified().ok());
+                (path, len, modified)
+            })
+            .collect())
+    }
+}
+
+/// Scans the voice directory at startup, logs every rejected file and fails when no clip can
+/// be sent at all.
+pub fn check_voice_library() -> Result<usize, AppError> {
+    let dir = get_voice_dir()?;
+    let library = VOICES.scan(&dir)?;
+    VOICES.report(&library);
+    if library.voices.is_empty() {
+        return Err(AppError::Media(format!(
+            "no usable voice clip in {}, {} file(s) rejected",
+            dir.display(),
+            library.rejected.len()
+        )));
+    }
+    let usable = library.voices.len();
+    VOICES.replace(library);
+    Ok(usable)
+}
+
+/// Scans the extras directory at startup when one is set and logs every rejected file. Extras
+/// are optional, a directory that cannot be read leaves the library empty.
+pub fn check_extra_library() -> usize {
+    let library = match get_extra_dir() {
+        Ok(dir) => EXTRAS.scan(&dir).unwrap_or_else(|error| {
+            log::error!("{} {} is empty.", error, EXTRAS.name);
+            Library::default()
+        }),
+        Err(_) => return 0,
+    };
+    EXTRAS.report(&library);
+    let usable = library.voices.len();
+    EXTRAS.replace(library);
+    usable
+}
+
+/// Watches the voice directory, the extras directory when set, and their manifests, rescanning
+/// a library whenever a file is added, removed or modified.
+pub fn spawn_media_watcher() {
+    VOICES.spawn_watcher();
+    if get_extra_dir().is_ok() {
+        EXTRAS.spawn_watcher();
+    }
+}
+
+/// Writes an uploaded clip into the voice directory, converting it when needed, lists it in
+/// the manifest and reloads the library. The file is named after the clip's title.
+pub(crate) fn add_clip(data: &[u8], extension: &str, mut clip: Clip) -> Result<Voice, AppError> {
+    let _guard = ADD_CLIP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = get_voice_dir()?;
+    let stem = file_stem(clip.title.as_deref().unwrap_or_default());
+    let mut name = format!("{}.{}", stem, extension);
+    let mut n = 2;
+    while dir.join(&name).exists() {
+        name = format!("{}-{}.{}", stem, n, extension);
+        n += 1;
+    }
+    let path = dir.join(&name);
+    // Hidden until the manifest lists it, so the watcher never picks up a clip without its title.
+    let partial = dir.join(format!(".{}.part", name));
+    write(&partial, data)?;
+    let (sent, format) = match ingest_as(&partial, extension) {
+        Ok(ingested) => ingested,
+        Err(reason) => {
+            let _ = remove_file(&partial);
+            return Err(AppError::Media(format!("clip rejected, {}", reason)));
         }
     };
 
-    let dir = read_dir(dir_path);
-    let dir = match dir {
-        Ok(dir) => dir,
+    clip.file = relative_name(&dir, &path);
+    let manifest_path = get_manifest_path(&dir);
+    let listed = read_manifest(&manifest_path).and_then(|mut manifest| {
+        manifest.clips.push(clip.clone());
+        write_atomic(
+            &manifest_path,
+            serde_json::to_string_pretty(&manifest)?.as_bytes(),
+        )
+    });
+    if let Err(error) = listed.and_then(|_| rename(&partial, &path).map_err(AppError::from)) {
+        let _ = remove_file(&partial);
+        return Err(error);
+    }
+    log::info!("Voice {} added to the library.", path.display());
+
+    VOICES.replace(VOICES.scan(&dir)?);
+    let sent = if sent == partial { path } else { sent };
+    Ok(Voice {
+        hash: hash_of(&sent),
+        path: sent,
+        format,
+        clip,
+    })
+}
+
+/// Content hash of a clip that is about to enter the library. A clip that cannot be read is
+/// still listed and is uploaded without caching its `file_id`.
+fn hash_of(path: &Path) -> Option<String> {
+    match content_hash(path) {
+        Ok(hash) => Some(hash),
         Err(error) => {
-            log::error!("AppError::io: Bot voice dir read error!");
-            panic!(
-                "AppError::io: Bot voice dir read error! Details: {:?}",
-                error
-            );
+            log::warn!("{} Voice: {} is uploaded uncached.", error, path.display());
+            None
+        }
+    }
+}
+
+// Letters and digits of the title, any script, joined by dashes.
+fn file_stem(title: &str) -> String {
+    let words: Vec<String> = title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        String::from("clip")
+    } else {
+        words.join("-")
+    }
+}
+
+// Manifest entries use '/' between directories on every platform.
+fn relative_name(dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// `BOT_VOICE_MANIFEST` may name the manifest through a relative path or a symlink.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn collect_files(
+    dir: &Path,
+    recursive: bool,
+    files: &mut Vec<PathBuf>,
+    skipped: &mut Vec<Rejected>,
+) -> Result<(), AppError> {
+    let mut entries = read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        // Hidden files are partial uploads or system clutter like .DS_Store.
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // Links to files are followed, links to directories are not, they could form a loop.
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() && recursive {
+            collect_files(&path, recursive, files, skipped)?;
+        } else if file_type.is_dir() {
+            skipped.push(Rejected {
+                path,
+                reason: String::from("is a directory, set BOT_VOICE_RECURSIVE to scan it"),
+            });
+        } else if path.is_file() {
+            files.push(path);
+        } else if file_type.is_symlink() && path.is_dir() {
+            skipped.push(Rejected {
+                path,
+                reason: String::from("is a link to a directory, which is not followed"),
+            });
         }
+    }
+    Ok(())
+}
+
+// Turns a file of the voice directory into something Telegram can play: OGG/Opus as is,
+// other audio converted to OGG/Opus, MP3 and M4A sent as music when conversion fails.
+fn ingest(path: &Path) -> Result<(PathBuf, Format), String> {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy())
+        .unwrap_or_default();
+    ingest_as(path, &extension)
+}
+
+// Like `ingest`, for a file whose name does not end in its real extension yet.
+fn ingest_as(path: &Path, extension: &str) -> Result<(PathBuf, Format), String> {
+    let reason = match validate_voice(path) {
+        Ok(_) => return Ok((path.to_path_buf(), Format::Voice)),
+        Err(reason) => reason,
     };
-    return dir;
+    let extension = extension.to_ascii_lowercase();
+    if !TRANSCODABLE.contains(&extension.as_str()) || !path.is_file() {
+        return Err(reason);
+    }
+    let error = match transcode(path) {
+        Ok(converted) => return Ok((converted, Format::Voice)),
+        Err(error) => error,
+    };
+    let size = metadata(path).map(|meta| meta.len()).unwrap_or(u64::MAX);
+    if SENDABLE_AUDIO.contains(&extension.as_str()) && size <= MAX_VOICE_SIZE {
+        log::warn!(
+            "AppError::media: {} not converted, {}. Sent as audio.",
+            path.display(),
+            error
+        );
+        return Ok((path.to_path_buf(), Format::Audio));
+    }
+    Err(format!("conversion failed, {}", error))
+}
+
+// Converts the clip to mono OGG/Opus with EBU R128 loudness normalization. Results are cached by
+// content hash, so a clip is only converted once.
+fn transcode(path: &Path) -> Result<PathBuf, String> {
+    let hash = content_hash(path).map_err(|error| error.to_string())?;
+    let dir = get_setting_dir()
+        .map_err(|error| error.to_string())?
+        .cache_dir()
+        .join("voices");
+    let output = dir.join(format!("{}.ogg", hash));
+    if validate_voice(&output).is_ok() {
+        return Ok(output);
+    }
+    create_dir_all(&dir).map_err(|error| error.to_string())?;
+
+    let partial = dir.join(format!("{}.ogg.part", hash));
+    let ffmpeg = env::var("BOT_FFMPEG")
+        .ok()
+        .filter(|ffmpeg| !ffmpeg.is_empty())
+        .unwrap_or_else(|| String::from("ffmpeg"));
+    let result = Command::new(&ffmpeg)
+        .args(["-y", "-v", "error", "-i"])
+        .arg(path)
+        .args([
+            "-vn",
+            "-ac",
+            "1",
+            "-af",
+            "loudnorm=I=-16:TP=-1.5:LRA=11",
+            "-c:a",
+            "libopus",
+            "-b:a",
+            "48k",
+            "-f",
+            "ogg",
+        ])
+        .arg(&partial)
+        .output()
+        .map_err(|error| format!("{} not runnable: {}", ffmpeg, error))?;
+    if !result.status.success() {
+        let _ = remove_file(&partial);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!(
+            "{} exited with {}: {}",
+            ffmpeg,
+            result.status,
+            stderr.lines().last().unwrap_or_default()
+        ));
+    }
+    rename(&partial, &output).map_err(|error| error.to_string())?;
+    validate_voice(&output)?;
+    log::info!(
+        "Voice {} converted to {}.",
+        path.display(),
+        output.display()
+    );
+    Ok(output)
+}
+
+/// Checks that the file is an OGG container whose first stream is Opus, the only format
+/// Telegram shows as a voice note, and that it fits the upload limit.
+pub(crate) fn validate_voice(path: &Path) -> Result<(), String> {
+    let meta = metadata(path).map_err(|_| String::from("not found"))?;
+    if !meta.is_file() {
+        return Err(String::from("not a file"));
+    }
+    if meta.len() > MAX_VOICE_SIZE {
+        return Err(format!(
+            "{} bytes exceeds the {} bytes limit",
+            meta.len(),
+            MAX_VOICE_SIZE
+        ));
+    }
+    // The first OGG page starts with "OggS" and carries the codec header at byte 28.
+    let mut header = [0u8; 36];
+    let mut file = File::open(path).map_err(|error| error.to_string())?;
+    if file.read_exact(&mut header).is_err() || &header[..4] != b"OggS" {
+        return Err(String::from("not an OGG file"));
+    }
+    if &header[28..36] != b"OpusHead" {
+        return Err(String::from("OGG file not encoded with Opus"));
+    }
+    Ok(())
+}
+
+/// Picks a clip at random, weighted by the manifest, from those not `played` yet. Once every
+/// clip was played it draws from all of them again, leaving out the last clip so it never
+/// plays twice in a row. Clips added to or removed from the library join or leave the bag.
+pub(crate) fn choose_voice<'a, I>(voices: I, played: &[String]) -> Result<&'a Voice, AppError>
+where
+    I: IntoIterator<Item = &'a Voice>,
+{
+    let playable: Vec<&Voice> = voices.into_iter().filter(|v| v.clip.weight > 0).collect();
+    if playable.is_empty() {
+        return Err(AppError::Media(String::from("voice dir is empty")));
+    }
+    let mut bag: Vec<&Voice> = playable
+        .iter()
+        .copied()
+        .filter(|v| !played.iter().any(|key| key == v.key()))
+        .collect();
+    if bag.is_empty() {
+        let last = played.last();
+        bag = playable
+            .iter()
+            .copied()
+            .filter(|v| playable.len() == 1 || last.map(String::as_str) != Some(v.key()))
+            .collect();
+    }
+    let weights = WeightedIndex::new(bag.iter().map(|v| v.clip.weight))
+        .map_err(|error| AppError::Media(error.to_string()))?;
+    Ok(bag[weights.sample(&mut rand::thread_rng())])
+}
+
+/// Records a clip that was sent in the shuffle bag `choose_voice` drew it from, refilling the
+/// bag first when every clip had been played.
+pub(crate) fn mark_played<'a, I>(voices: I, played: &mut Vec<String>, voice: &Voice)
+where
+    I: IntoIterator<Item = &'a Voice>,
+{
+    let playable: Vec<&Voice> = voices.into_iter().filter(|v| v.clip.weight > 0).collect();
+    // Forget clips that left the library so the bag doesn't grow stale.
+    played.retain(|key| playable.iter().any(|v| v.key() == key));
+    if played.iter().any(|key| key == voice.key()) {
+        played.clear();
+    }
+    played.push(String::from(voice.key()));
 }
 
-#[allow(dead_code)]
-pub(crate) fn get_random_voice(dir: ReadDir) -> PathBuf {
-    let mut rng = rand::thread_rng();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voices(names: &[&str]) -> Vec<Voice> {
+        names
+            .iter()
+            .map(|name| Voice {
+                path: PathBuf::from(name),
+                format: Format::Voice,
+                clip: Clip::new(String::from(*name)),
+                hash: None,
+            })
+            .collect()
+    }
+
+    // Draws and records `n` clips like a chat receiving `n` reminders.
+    fn draw(voices: &[Voice], played: &mut Vec<String>, n: usize) -> Vec<String> {
+        (0..n)
+            .map(|_| {
+                let voice = choose_voice(voices, played).unwrap();
+                mark_played(voices, played, voice);
+                voice.clip.file.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_bag_never_repeats_until_exhausted() {
+        let voices = voices(&["a", "b", "c", "d", "e"]);
+        let mut played = vec![];
+        for _ in 0..20 {
+            let mut round = draw(&voices, &mut played, voices.len());
+            round.sort();
+            assert_eq!(round, vec!["a", "b", "c", "d", "e"]);
+        }
+    }
+
+    #[test]
+    fn refilled_bag_skips_the_last_clip() {
+        let voices = voices(&["a", "b"]);
+        let mut played = vec![];
+        let sent = draw(&voices, &mut played, 40);
+        assert!(sent.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn single_clip_repeats() {
+        let voices = voices(&["a"]);
+        let mut played = vec![];
+        assert_eq!(draw(&voices, &mut played, 3), vec!["a", "a", "a"]);
+    }
+
+    #[test]
+    fn unsent_clips_stay_in_the_bag() {
+        let voices = voices(&["a", "b"]);
+        let played = vec![String::from("a")];
+        for _ in 0..10 {
+            assert_eq!(choose_voice(&voices, &played).unwrap().clip.file, "b");
+        }
+    }
+
+    #[test]
+    fn removed_clips_leave_the_bag() {
+        let mut voices = voices(&["a", "b", "c"]);
+        let mut played = vec![String::from("gone"), String::from("a")];
+        voices[2].clip.weight = 0;
+        let voice = choose_voice(&voices, &played).unwrap();
+        assert_eq!(voice.clip.file, "b");
+        mark_played(&voices, &mut played, voice);
+        assert_eq!(played, vec!["a", "b"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collect_files_does_not_follow_directory_links() {
+        let dir = env::temp_dir().join(format!("yinchabot-collect-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(dir.join("pack")).unwrap();
+        write(dir.join("pack/a.ogg"), b"").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("pack/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("pack/a.ogg"), dir.join("b.ogg")).unwrap();
+
+        let mut files = vec![];
+        let mut skipped = vec![];
+        collect_files(&dir, true, &mut files, &mut skipped).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, vec![dir.join("b.ogg"), dir.join("pack/a.ogg")]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].path, dir.join("pack/loop"));
+    }
+
+    #[test]
+    fn packs_ignore_case() {
+        let mut voices = voices(&["Cantonese/a.ogg", "cantonese/b.ogg", "c.ogg"]);
+        voices[0].clip.packs = vec![String::from("CANTONESE")];
+        voices[2].clip.packs = vec![String::from("Classic")];
+        let library = Library {
+            voices,
+            ..Library::default()
+        };
+        let packs = library.packs();
+        let packs: Vec<(&str, usize)> = packs.iter().map(|(p, n)| (p.as_str(), *n)).collect();
+        assert_eq!(packs, vec![("cantonese", 2), ("classic", 1)]);
+        assert!(library.voices[0].in_any_pack(&[String::from("cantonese")]));
+        assert!(library.voices[2].in_any_pack(&[String::from("classic")]));
+        assert!(!library.voices[2].in_any_pack(&[String::from("cantonese")]));
+    }
+
+    #[test]
+    fn shuffle_bag_tells_file_id_entries_apart() {
+        let mut voices = voices(&["placeholder", "placeholder"]);
+        voices[0].clip.file_id = Some(String::from("id-a"));
+        voices[1].clip.file_id = Some(String::from("id-b"));
+        let mut played = vec![];
+        draw(&voices, &mut played, 2);
+        played.sort();
+        assert_eq!(played, vec!["id-a", "id-b"]);
+    }
+
+    #[test]
+    fn scan_rejects_file_ids_of_other_kinds() {
+        let source = Source {
+            name: "Test library",
+            dir: get_voice_dir,
+            manifest: |dir| dir.join(MANIFEST_NAME),
+            recursive: || false,
+            ingest,
+            formats: &[Format::Voice, Format::Audio],
+            catalog: &CATALOG,
+        };
+        let dir = env::temp_dir().join(format!("yinchabot-scan-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let manifest = r#"{"auto_discover": false, "clips": [
+            {"file": "a", "file_id": "voice-id", "kind": "voice"},
+            {"file": "b", "file_id": "sticker-id", "kind": "sticker"}
+        ]}"#;
+        write(dir.join(MANIFEST_NAME), manifest).unwrap();
 
-    let choose = dir.choose::<ThreadRng>(&mut rng).expect("AppError::io");
-    let file = choose
-        .expect("AppError::io: choose voice file error")
-        .path();
+        let library = source.scan(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let keys: Vec<&str> = library.voices.iter().map(Voice::key).collect();
+        assert_eq!(keys, vec!["voice-id"]);
+        assert_eq!(library.rejected.len(), 1);
+        assert_eq!(library.rejected[0].path, PathBuf::from("sticker-id"));
+    }
 
-    return file;
+    #[test]
+    fn empty_library_is_an_error() {
+        assert!(choose_voice(&voices(&[]), &[]).is_err());
+    }
 }
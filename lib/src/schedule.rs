@@ -0,0 +1,309 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::timezone::Zone;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc};
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+const DAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const DAY_FULL_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// When a subscription gets its reminders, written as `HH:MM[,HH:MM...] [on <days>]`, e.g.
+/// `15:00`, `10:30,15:00 on Mon-Fri` or `15:00 on Sat,Sun`. Times are local to the chat's zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    times: Vec<NaiveTime>,
+    // Indexed by days from Monday.
+    days: [bool; 7],
+}
+
+impl Schedule {
+    /// First slot strictly after `after`.
+    pub fn next_after(&self, zone: &Zone, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let today = zone.local(&after).date();
+        (0..=7)
+            .map(|days| today + Duration::days(days))
+            .filter(|date| self.days[date.weekday().num_days_from_monday() as usize])
+            .flat_map(|date| self.times.iter().map(move |time| date.and_time(*time)))
+            .filter_map(|t| zone.from_local(&t))
+            .find(|t| *t > after)
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            times: vec![NaiveTime::from_hms(15, 0, 0)],
+            days: [true; 7],
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: String| ScheduleError {
+            input: String::from(s),
+            reason,
+        };
+        let lower = s.trim().to_ascii_lowercase();
+        let (times, days) = match lower.split_once(" on ") {
+            Some((times, days)) => (times, Some(days)),
+            None => (lower.as_str(), None),
+        };
+
+        let mut parsed_times = vec![];
+        for time in times.split(|c: char| c == ',' || c.is_whitespace()) {
+            if time.is_empty() {
+                continue;
+            }
+            let parsed = NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| err(format!("'{}' is not a time like 15:00", time)))?;
+            parsed_times.push(parsed);
+        }
+        if parsed_times.is_empty() {
+            return Err(err(String::from("no time given")));
+        }
+        parsed_times.sort();
+        parsed_times.dedup();
+
+        let days = match days {
+            Some(days) => parse_days(days).map_err(err)?,
+            None => [true; 7],
+        };
+
+        Ok(Schedule {
+            times: parsed_times,
+            days,
+        })
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let times: Vec<String> = self
+            .times
+            .iter()
+            .map(|t| t.format("%H:%M").to_string())
+            .collect();
+        write!(f, "{}", times.join(","))?;
+        if self.days.iter().all(|d| *d) {
+            return Ok(());
+        }
+
+        // Runs of three or more days are written as ranges, like "Mon-Fri".
+        let mut parts = vec![];
+        let mut i = 0;
+        while i < 7 {
+            if !self.days[i] {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i + 1 < 7 && self.days[i + 1] {
+                i += 1;
+            }
+            match i - start {
+                0 => parts.push(String::from(DAY_NAMES[start])),
+                1 => {
+                    parts.push(String::from(DAY_NAMES[start]));
+                    parts.push(String::from(DAY_NAMES[i]));
+                }
+                _ => parts.push(format!("{}-{}", DAY_NAMES[start], DAY_NAMES[i])),
+            }
+            i += 1;
+        }
+        write!(f, " on {}", parts.join(","))
+    }
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    for part in s.split(|c: char| c == ',' || c.is_whitespace()) {
+        match part {
+            "" => continue,
+            "daily" | "everyday" => days = [true; 7],
+            "weekdays" => days[..5].iter_mut().for_each(|d| *d = true),
+            "weekends" => days[5..].iter_mut().for_each(|d| *d = true),
+            _ => match part.split_once('-') {
+                // Ranges may wrap around the week, like "Sat-Mon".
+                Some((from, to)) => {
+                    let (from, to) = (parse_day(from)?, parse_day(to)?);
+                    let mut i = from;
+                    loop {
+                        days[i] = true;
+                        if i == to {
+                            break;
+                        }
+                        i = (i + 1) % 7;
+                    }
+                }
+                None => days[parse_day(part)?] = true,
+            },
+        }
+    }
+    if !days.iter().any(|d| *d) {
+        return Err(String::from("no day given"));
+    }
+    Ok(days)
+}
+
+fn parse_day(s: &str) -> Result<usize, String> {
+    DAY_FULL_NAMES
+        .iter()
+        .position(|name| s.len() >= 3 && name.starts_with(s))
+        .ok_or_else(|| format!("'{}' is not a day like Mon", s))
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleError {
+    pub input: String,
+    pub reason: String,
+}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid schedule '{}': {}. Expected something like '15:00' or '10:30,15:00 on Mon-Fri'.",
+            self.input, self.reason
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn parse(s: &str) -> Schedule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_times_and_days() {
+        let schedule = parse("15:00,10:30 on Mon-Fri");
+        assert_eq!(
+            schedule.times,
+            vec![
+                NaiveTime::from_hms(10, 30, 0),
+                NaiveTime::from_hms(15, 0, 0)
+            ]
+        );
+        assert_eq!(schedule.days, [true, true, true, true, true, false, false]);
+        assert_eq!(parse("15:00"), Schedule::default());
+    }
+
+    #[test]
+    fn parses_day_names_and_groups() {
+        assert_eq!(
+            parse("15:00 on weekends").days,
+            parse("15:00 on Sat,Sun").days
+        );
+        assert_eq!(
+            parse("15:00 on weekdays").days,
+            parse("15:00 on mon-fri").days
+        );
+        assert_eq!(
+            parse("15:00 on Tuesday thu").days,
+            [false, true, false, true, false, false, false]
+        );
+        // Ranges wrap around the end of the week.
+        assert_eq!(
+            parse("15:00 on Sat-Mon").days,
+            [true, false, false, false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        for s in &[
+            "",
+            "on Mon",
+            "25:00",
+            "3pm",
+            "15:00 on",
+            "15:00 on Mo",
+            "15:00 on Funday",
+        ] {
+            assert!(s.parse::<Schedule>().is_err(), "{:?} should not parse", s);
+        }
+    }
+
+    #[test]
+    fn displays_ranges_compactly() {
+        assert_eq!(parse("10:30, 15:00").to_string(), "10:30,15:00");
+        assert_eq!(parse("15:00 on Mon-Fri").to_string(), "15:00 on Mon-Fri");
+        assert_eq!(
+            parse("15:00 on Mon,Tue,Sat").to_string(),
+            "15:00 on Mon,Tue,Sat"
+        );
+        assert_eq!(
+            parse("15:00 on Sat-Mon").to_string(),
+            "15:00 on Mon,Sat,Sun"
+        );
+        assert_eq!(parse("15:00 on daily").to_string(), "15:00");
+    }
+
+    #[test]
+    fn next_after_skips_days_off() {
+        let zone = Zone::default();
+        // 2021-06-04 is a Friday, 16:00 in UTC+8.
+        let friday = Utc.ymd(2021, 6, 4).and_hms(8, 0, 0);
+        assert_eq!(
+            parse("10:30,15:00 on Mon-Fri").next_after(&zone, friday),
+            Some(Utc.ymd(2021, 6, 7).and_hms(2, 30, 0))
+        );
+        assert_eq!(
+            parse("15:00").next_after(&zone, Utc.ymd(2021, 6, 4).and_hms(6, 0, 0)),
+            Some(Utc.ymd(2021, 6, 4).and_hms(7, 0, 0))
+        );
+    }
+
+    #[test]
+    fn next_after_follows_dst() {
+        let zone = Zone::Named(chrono_tz::Europe::London);
+        // 15:00 in London is 15:00 UTC in winter and 14:00 UTC in summer.
+        let before = Utc.ymd(2021, 3, 27).and_hms(16, 0, 0);
+        assert_eq!(
+            parse("15:00").next_after(&zone, before),
+            Some(Utc.ymd(2021, 3, 28).and_hms(14, 0, 0))
+        );
+    }
+
+    #[test]
+    fn next_after_skips_time_lost_to_dst() {
+        // London clocks jumped from 01:00 to 02:00 on 2021-03-28.
+        let zone = Zone::Named(chrono_tz::Europe::London);
+        let before = Utc.ymd(2021, 3, 27).and_hms(12, 0, 0);
+        assert_eq!(
+            parse("01:30").next_after(&zone, before),
+            Some(Utc.ymd(2021, 3, 29).and_hms(0, 30, 0))
+        );
+    }
+}
@@ -0,0 +1,692 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{
+    error::{AppError, SendFailure},
+    handler::voice_handler,
+    ledger::Ledger,
+    schedule::Schedule,
+    settings::{CatchUp, Settings, Subscribe},
+    shuffle::ShuffleBags,
+    throttle::Throttle,
+    timezone::Zone,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use teloxide::{prelude::AutoSend, Bot};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+// Longest time the scheduler sleeps before re-checking the wall clock.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+const LATE_CAPTION: &str = "Sorry I'm late! 饮茶先啦！";
+// Slots found due later than this, after a suspend or a clock jump, follow the catch-up policy.
+const ON_TIME_TOLERANCE_SECS: i64 = 60;
+// Attempts per reminder, transient failures wait 2s, 4s, 8s... in between.
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Job {
+    at: DateTime<Utc>,
+    chat_id: i64,
+}
+
+#[derive(Debug)]
+enum SchedulerEvent {
+    Reload,
+    CatchUp,
+    Delivered {
+        chat_id: i64,
+        slot: DateTime<Utc>,
+        sent_at: DateTime<Utc>,
+    },
+    Failed {
+        chat_id: i64,
+        slot: DateTime<Utc>,
+    },
+    ChatGone {
+        chat_id: i64,
+        slot: DateTime<Utc>,
+    },
+    Migrated {
+        from: i64,
+        to: i64,
+    },
+}
+
+/// Handle to the reminder scheduler task, cheap to clone into update handlers.
+#[derive(Debug, Clone)]
+pub struct SchedulerHandle {
+    tx: UnboundedSender<SchedulerEvent>,
+}
+
+impl SchedulerHandle {
+    /// Asks the scheduler to re-read the subscriptions, call it after every settings change.
+    pub fn reload(&self) {
+        if self.tx.send(SchedulerEvent::Reload).is_err() {
+            log::error!("AppError::scheduler: scheduler task is not running!");
+        }
+    }
+    /// Asks the scheduler to apply each chat's catch-up policy to missed reminders, call it
+    /// once the bot is back online.
+    pub fn catch_up(&self) {
+        if self.tx.send(SchedulerEvent::CatchUp).is_err() {
+            log::error!("AppError::scheduler: scheduler task is not running!");
+        }
+    }
+    /// Moves a group's subscription and delivery history to the supergroup it became.
+    pub fn migrate(&self, from: i64, to: i64) {
+        if self.tx.send(SchedulerEvent::Migrated { from, to }).is_err() {
+            log::error!("AppError::scheduler: scheduler task is not running!");
+        }
+    }
+}
+
+pub fn spawn_scheduler(bot: AutoSend<Bot>) -> SchedulerHandle {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(run(bot, tx.clone(), rx));
+    SchedulerHandle { tx }
+}
+
+// Reminders sent while earlier ones are still in flight, reported once all of them are done.
+#[derive(Debug)]
+struct Broadcast {
+    started: Instant,
+    sent: usize,
+    failed: usize,
+}
+
+struct Scheduler {
+    bot: AutoSend<Bot>,
+    tx: UnboundedSender<SchedulerEvent>,
+    subs: HashMap<i64, Subscribe>,
+    queue: BinaryHeap<Reverse<Job>>,
+    ledger: Ledger,
+    in_flight: HashSet<(i64, DateTime<Utc>)>,
+    throttle: Arc<Throttle>,
+    broadcast: Option<Broadcast>,
+}
+
+impl Scheduler {
+    fn new(bot: AutoSend<Bot>, tx: UnboundedSender<SchedulerEvent>) -> Self {
+        // Without a readable ledger reminders still go out, only duplicate detection is lost.
+        let ledger = Ledger::new().unwrap_or_else(|error| {
+            log::error!("{} Delivery ledger is kept in memory only.", error);
+            Ledger::default()
+        });
+        Scheduler {
+            bot,
+            tx,
+            subs: HashMap::new(),
+            queue: BinaryHeap::new(),
+            ledger,
+            in_flight: HashSet::new(),
+            throttle: Arc::new(Throttle::default()),
+            broadcast: None,
+        }
+    }
+
+    fn reload(&mut self, after: DateTime<Utc>) {
+        let set = match Settings::new() {
+            Ok(set) => set,
+            Err(error) => {
+                log::error!("{} Scheduler keeps the previous subscriptions.", error);
+                return;
+            }
+        };
+        self.subs = set
+            .subscribe
+            .into_iter()
+            .filter(|sub| sub.active)
+            .map(|sub| (sub.chat_id, sub))
+            .collect();
+        self.queue = self
+            .subs
+            .values()
+            .filter_map(|sub| next_job(sub, after))
+            .map(Reverse)
+            .collect();
+        log::trace!("Scheduler loaded {} subscription(s).", self.subs.len());
+    }
+
+    fn sleep_duration(&self) -> Duration {
+        match self.queue.peek() {
+            Some(Reverse(job)) => (job.at - Utc::now())
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+                .min(MAX_SLEEP),
+            None => MAX_SLEEP,
+        }
+    }
+
+    fn fire_due(&mut self, now: DateTime<Utc>) {
+        let mut overdue = false;
+        while let Some(Reverse(job)) = self.queue.peek().copied() {
+            if job.at > now {
+                break;
+            }
+            self.queue.pop();
+            // Slots skipped over while the bot was asleep are never queued.
+            let next = match self.subs.get(&job.chat_id) {
+                Some(sub) => next_job(sub, now),
+                None => continue,
+            };
+            if now - job.at > ChronoDuration::seconds(ON_TIME_TOLERANCE_SECS) {
+                overdue = true;
+            } else {
+                self.fire(job, None);
+            }
+            if let Some(next) = next {
+                self.queue.push(Reverse(next));
+            }
+        }
+        if overdue {
+            log::warn!("Scheduler woke up late, catching up missed reminders.");
+            self.catch_up(now);
+        }
+    }
+
+    fn fire(&mut self, job: Job, caption: Option<String>) {
+        let key = (job.chat_id, job.at);
+        if self.ledger.is_delivered(job.chat_id, job.at) || self.in_flight.contains(&key) {
+            log::warn!(
+                "Reminder for chat id: {} at {} already sent, skipped.",
+                job.chat_id,
+                job.at
+            );
+            return;
+        }
+        self.in_flight.insert(key);
+        self.broadcast.get_or_insert_with(|| Broadcast {
+            started: Instant::now(),
+            sent: 0,
+            failed: 0,
+        });
+
+        let bot = self.bot.clone();
+        let tx = self.tx.clone();
+        let throttle = self.throttle.clone();
+        let sub = self.subs.get(&job.chat_id).cloned();
+        log::trace!("Reminder due for chat id: {} at {}.", job.chat_id, job.at);
+        // The send runs in its own task so a panic in it still clears `in_flight`.
+        let send = tokio::spawn(async move {
+            let (chat_id, result) =
+                deliver(&bot, &tx, &throttle, job.chat_id, sub.as_ref(), caption).await;
+            match result {
+                Ok(_) => SchedulerEvent::Delivered {
+                    chat_id,
+                    slot: job.at,
+                    sent_at: Utc::now(),
+                },
+                Err(error) if error.send_failure() == SendFailure::ChatGone => {
+                    log::warn!("{} Chat id: {} is gone.", error, chat_id);
+                    SchedulerEvent::ChatGone {
+                        chat_id,
+                        slot: job.at,
+                    }
+                }
+                Err(error) => {
+                    log::error!("{} Chat id: {}.", error, chat_id);
+                    SchedulerEvent::Failed {
+                        chat_id,
+                        slot: job.at,
+                    }
+                }
+            }
+        });
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let event = send.await.unwrap_or_else(|error| {
+                log::error!(
+                    "AppError::scheduler: reminder for chat id: {} aborted, {}",
+                    job.chat_id,
+                    error
+                );
+                SchedulerEvent::Failed {
+                    chat_id: job.chat_id,
+                    slot: job.at,
+                }
+            });
+            let _ = tx.send(event);
+        });
+    }
+
+    // Recorded in memory right away, written to disk once the broadcast is done.
+    fn delivered(&mut self, chat_id: i64, slot: DateTime<Utc>, sent_at: DateTime<Utc>) {
+        self.in_flight.remove(&(chat_id, slot));
+        self.ledger.record(chat_id, slot, sent_at);
+        self.finish(true);
+    }
+
+    fn failed(&mut self, chat_id: i64, slot: DateTime<Utc>) {
+        self.in_flight.remove(&(chat_id, slot));
+        self.finish(false);
+    }
+
+    // Counts a finished reminder towards the running broadcast and reports it once drained.
+    fn finish(&mut self, sent: bool) {
+        let broadcast = match self.broadcast.as_mut() {
+            Some(broadcast) => broadcast,
+            None => return,
+        };
+        if sent {
+            broadcast.sent += 1;
+        } else {
+            broadcast.failed += 1;
+        }
+        if self.in_flight.is_empty() {
+            if let Err(error) = self.ledger.save() {
+                log::error!("{} Deliveries of this broadcast not persisted.", error);
+            }
+            log::info!(
+                "Broadcast finished in {:.1?}, {} sent, {} failed.",
+                broadcast.started.elapsed(),
+                broadcast.sent,
+                broadcast.failed
+            );
+            self.broadcast = None;
+        }
+    }
+
+    fn migrated(&mut self, from: i64, to: i64, cursor: DateTime<Utc>) {
+        let guard = Settings::lock();
+        let migrated = Settings::new().and_then(|mut set| {
+            let migrated = set.migrate_sub(from, to);
+            set.save()?;
+            Ok(migrated)
+        });
+        drop(guard);
+        match migrated {
+            Ok(true) => log::info!("Bot migrated subscription of chat id: {} to {}.", from, to),
+            Ok(false) => {}
+            Err(error) => log::error!("{} Chat id: {} not migrated to {}.", error, from, to),
+        }
+        if let Err(error) = self.ledger.migrate(from, to).save() {
+            log::error!("{} Deliveries of chat id: {} not migrated.", error, from);
+        }
+        if let Err(error) = ShuffleBags::lock().migrate(from, to).save() {
+            log::error!("{} Shuffle bag of chat id: {} not migrated.", error, from);
+        }
+        self.in_flight = self
+            .in_flight
+            .drain()
+            .map(|(chat_id, slot)| (if chat_id == from { to } else { chat_id }, slot))
+            .collect();
+        self.reload(cursor);
+    }
+
+    // The bot was kicked or the chat no longer exists, stop scheduling it.
+    fn chat_gone(&mut self, chat_id: i64, slot: DateTime<Utc>, cursor: DateTime<Utc>) {
+        self.in_flight.remove(&(chat_id, slot));
+        self.finish(false);
+        let guard = Settings::lock();
+        let deactivated = Settings::new().and_then(|mut set| {
+            let deactivated = set.deactivate_sub(chat_id);
+            set.save()?;
+            Ok(deactivated)
+        });
+        drop(guard);
+        match deactivated {
+            Ok(true) => log::info!("Bot deactivated subscription of chat id: {}.", chat_id),
+            Ok(false) => {}
+            Err(error) => log::error!("{} Chat id: {} not deactivated.", error, chat_id),
+        }
+        self.reload(cursor);
+    }
+
+    // Slots since the last recorded delivery or subscription of each chat that were never sent.
+    // Chats with neither look back as far as their catch-up policy would still send.
+    fn missed_slots(&self, now: DateTime<Utc>) -> Vec<Job> {
+        self.subs
+            .values()
+            .filter_map(|sub| {
+                let since = match (self.ledger.last_slot(sub.chat_id), sub.subscribed_at) {
+                    (Some(last), Some(subscribed)) => last.max(subscribed),
+                    (last, subscribed) => last
+                        .or(subscribed)
+                        .or_else(|| catch_up_window(sub.catch_up).map(|window| now - window))?,
+                };
+                Some((sub, since))
+            })
+            .flat_map(|(sub, since)| {
+                let mut slots = vec![];
+                let mut after = since;
+                while let Some(job) = next_job(sub, after) {
+                    if job.at > now {
+                        break;
+                    }
+                    if !self.ledger.is_delivered(job.chat_id, job.at)
+                        && !self.in_flight.contains(&(job.chat_id, job.at))
+                    {
+                        slots.push(job);
+                    }
+                    after = job.at;
+                }
+                slots
+            })
+            .collect()
+    }
+
+    // Latest missed slot of each chat, with its policy and whether that still sends it. Older
+    // missed slots are dropped.
+    fn catch_up_jobs(&self, now: DateTime<Utc>) -> Vec<(Job, CatchUp, bool)> {
+        let mut latest: HashMap<i64, Job> = HashMap::new();
+        for job in self.missed_slots(now) {
+            let entry = latest.entry(job.chat_id).or_insert(job);
+            if job.at > entry.at {
+                *entry = job;
+            }
+        }
+
+        latest
+            .into_values()
+            .filter_map(|job| {
+                let policy = self.subs.get(&job.chat_id)?.catch_up;
+                let send = match policy {
+                    CatchUp::Skip => false,
+                    CatchUp::Late { within_minutes } => {
+                        now - job.at <= ChronoDuration::minutes(within_minutes.into())
+                    }
+                    CatchUp::Always => true,
+                };
+                Some((job, policy, send))
+            })
+            .collect()
+    }
+
+    fn catch_up(&mut self, now: DateTime<Utc>) {
+        for (job, policy, send) in self.catch_up_jobs(now) {
+            if send {
+                log::info!(
+                    "Catching up reminder for chat id: {} at {}, policy: {}.",
+                    job.chat_id,
+                    job.at,
+                    policy
+                );
+                self.fire(job, Some(String::from(LATE_CAPTION)));
+            } else {
+                log::warn!(
+                    "Reminder for chat id: {} at {} was missed, policy: {}.",
+                    job.chat_id,
+                    job.at,
+                    policy
+                );
+            }
+        }
+    }
+}
+
+async fn run(
+    bot: AutoSend<Bot>,
+    tx: UnboundedSender<SchedulerEvent>,
+    mut rx: UnboundedReceiver<SchedulerEvent>,
+) {
+    // Every slot up to `cursor` has been handled, so a reload never fires a slot twice and
+    // never drops one that fell due while the reload was pending.
+    let mut cursor = Utc::now();
+    let mut scheduler = Scheduler::new(bot, tx);
+    scheduler.reload(cursor);
+    scheduler.catch_up(cursor);
+
+    loop {
+        tokio::select! {
+            // The scheduler holds a sender itself, so the channel never closes.
+            Some(event) = rx.recv() => match event {
+                SchedulerEvent::Reload => scheduler.reload(cursor),
+                SchedulerEvent::CatchUp => scheduler.catch_up(cursor),
+                SchedulerEvent::Delivered { chat_id, slot, sent_at } => {
+                    scheduler.delivered(chat_id, slot, sent_at)
+                }
+                SchedulerEvent::Failed { chat_id, slot } => scheduler.failed(chat_id, slot),
+                SchedulerEvent::ChatGone { chat_id, slot } => {
+                    scheduler.chat_gone(chat_id, slot, cursor)
+                }
+                SchedulerEvent::Migrated { from, to } => scheduler.migrated(from, to, cursor),
+            },
+            _ = tokio::time::sleep(scheduler.sleep_duration()) => {
+                let now = Utc::now();
+                scheduler.fire_due(now);
+                cursor = now;
+            }
+        }
+    }
+}
+
+// Sends one reminder within the flood limits, retrying transient failures with exponential
+// backoff and following a group's migration to a supergroup. Returns the chat id the reminder
+// finally went to.
+async fn deliver(
+    bot: &AutoSend<Bot>,
+    tx: &UnboundedSender<SchedulerEvent>,
+    throttle: &Throttle,
+    mut chat_id: i64,
+    sub: Option<&Subscribe>,
+    caption: Option<String>,
+) -> (i64, Result<(), AppError>) {
+    let mut attempt = 1;
+    loop {
+        throttle.acquire(chat_id).await;
+        let error = match voice_handler(bot, throttle, chat_id, sub, caption.clone()).await {
+            Ok(_) => return (chat_id, Ok(())),
+            Err(error) => error,
+        };
+        let wait = match error.send_failure() {
+            SendFailure::Migrated(to) if to != chat_id => {
+                log::info!("Chat id: {} migrated to {}, resending.", chat_id, to);
+                let _ = tx.send(SchedulerEvent::Migrated { from: chat_id, to });
+                chat_id = to;
+                continue;
+            }
+            // Flood control applies to the whole bot, hold back the rest of the broadcast too.
+            SendFailure::Transient {
+                retry_after: Some(retry_after),
+            } if attempt < MAX_ATTEMPTS => {
+                throttle.pause(retry_after);
+                retry_after
+            }
+            SendFailure::Transient { .. } if attempt < MAX_ATTEMPTS => {
+                BASE_BACKOFF * 2u32.pow(attempt - 1)
+            }
+            _ => return (chat_id, Err(error)),
+        };
+        log::warn!(
+            "{} Chat id: {}, attempt {} of {}, retry in {:?}.",
+            error,
+            chat_id,
+            attempt,
+            MAX_ATTEMPTS,
+            wait
+        );
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+// How long ago a missed slot may be and still get sent. `Always` looks back a full week, which
+// covers one of every slot a schedule can have.
+fn catch_up_window(policy: CatchUp) -> Option<ChronoDuration> {
+    match policy {
+        CatchUp::Skip => None,
+        CatchUp::Late { within_minutes } => Some(ChronoDuration::minutes(within_minutes.into())),
+        CatchUp::Always => Some(ChronoDuration::weeks(1)),
+    }
+}
+
+fn next_job(sub: &Subscribe, after: DateTime<Utc>) -> Option<Job> {
+    let zone = match sub.zone() {
+        Ok(zone) => zone,
+        Err(error) => {
+            log::warn!(
+                "AppError::timezone: chat id: {}, {} Fall back to UTC+8.",
+                sub.chat_id,
+                error
+            );
+            Zone::default()
+        }
+    };
+    let schedule = match sub.schedule() {
+        Ok(schedule) => schedule,
+        Err(error) => {
+            log::warn!(
+                "AppError::schedule: chat id: {}, {} Fall back to {}.",
+                sub.chat_id,
+                error,
+                Schedule::default()
+            );
+            Schedule::default()
+        }
+    };
+    schedule.next_after(&zone, after).map(|at| Job {
+        at,
+        chat_id: sub.chat_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::prelude::RequesterExt;
+
+    // Ledger retention counts from the real clock, so the slots are kept close to it.
+    fn today(h: u32, m: u32) -> DateTime<Utc> {
+        Utc::now().date().and_hms(h, m, 0)
+    }
+
+    fn sub(chat_id: i64, catch_up: CatchUp, subscribed_at: Option<DateTime<Utc>>) -> Subscribe {
+        let mut sub = Subscribe::new(chat_id);
+        sub.update_tz("UTC");
+        sub.update_schedule(&"06:00,10:00".parse().unwrap());
+        sub.update_catch_up(catch_up);
+        sub.subscribed_at = subscribed_at;
+        sub
+    }
+
+    fn scheduler(subs: Vec<Subscribe>, ledger: Ledger) -> Scheduler {
+        let (tx, _) = mpsc::unbounded_channel();
+        Scheduler {
+            bot: Bot::new("0:test").auto_send(),
+            tx,
+            subs: subs.into_iter().map(|sub| (sub.chat_id, sub)).collect(),
+            queue: BinaryHeap::new(),
+            ledger,
+            in_flight: HashSet::new(),
+            throttle: Arc::new(Throttle::default()),
+            broadcast: None,
+        }
+    }
+
+    fn slots(jobs: Vec<Job>) -> Vec<(i64, DateTime<Utc>)> {
+        let mut slots: Vec<_> = jobs.iter().map(|job| (job.chat_id, job.at)).collect();
+        slots.sort();
+        slots
+    }
+
+    #[test]
+    fn missed_slots_start_after_the_last_delivery() {
+        let mut ledger = Ledger::default();
+        ledger.record(1, today(6, 0), today(6, 0));
+        let scheduler = scheduler(vec![sub(1, CatchUp::Always, None)], ledger);
+        assert_eq!(
+            slots(scheduler.missed_slots(today(12, 0))),
+            vec![(1, today(10, 0))]
+        );
+    }
+
+    #[test]
+    fn missed_slots_start_at_a_later_subscription() {
+        let mut ledger = Ledger::default();
+        ledger.record(1, today(6, 0) - ChronoDuration::days(1), today(6, 0));
+        let subscribed = Some(today(8, 0));
+        let scheduler = scheduler(vec![sub(1, CatchUp::Always, subscribed)], ledger);
+        assert_eq!(
+            slots(scheduler.missed_slots(today(12, 0))),
+            vec![(1, today(10, 0))]
+        );
+    }
+
+    #[test]
+    fn missed_slots_without_history_follow_the_policy_window() {
+        let subs = vec![
+            sub(1, CatchUp::Skip, None),
+            sub(2, CatchUp::Late { within_minutes: 30 }, None),
+            sub(3, CatchUp::Late { within_minutes: 30 }, None),
+        ];
+        let mut scheduler = scheduler(subs, Ledger::default());
+        scheduler.in_flight.insert((3, today(10, 0)));
+        assert_eq!(
+            slots(scheduler.missed_slots(today(10, 20))),
+            vec![(2, today(10, 0))]
+        );
+    }
+
+    #[test]
+    fn missed_slots_skip_delivered_and_in_flight_slots() {
+        let mut ledger = Ledger::default();
+        ledger.record(1, today(6, 0), today(6, 0));
+        ledger.record(1, today(10, 0), today(10, 0));
+        let subscribed = Some(today(0, 0));
+        let mut scheduler = scheduler(
+            vec![
+                sub(1, CatchUp::Always, subscribed),
+                sub(2, CatchUp::Always, subscribed),
+            ],
+            ledger,
+        );
+        scheduler.in_flight.insert((2, today(6, 0)));
+        assert_eq!(
+            slots(scheduler.missed_slots(today(12, 0))),
+            vec![(2, today(10, 0))]
+        );
+    }
+
+    #[test]
+    fn catch_up_sends_only_the_latest_slot_per_policy() {
+        let subscribed = Some(today(0, 0));
+        let subs = vec![
+            sub(1, CatchUp::Skip, subscribed),
+            sub(2, CatchUp::Late { within_minutes: 30 }, subscribed),
+            sub(
+                3,
+                CatchUp::Late {
+                    within_minutes: 180,
+                },
+                subscribed,
+            ),
+            sub(4, CatchUp::Always, subscribed),
+        ];
+        let scheduler = scheduler(subs, Ledger::default());
+        let mut decisions: Vec<(i64, DateTime<Utc>, bool)> = scheduler
+            .catch_up_jobs(today(12, 0))
+            .into_iter()
+            .map(|(job, _, send)| (job.chat_id, job.at, send))
+            .collect();
+        decisions.sort();
+        assert_eq!(
+            decisions,
+            vec![
+                (1, today(10, 0), false),
+                (2, today(10, 0), false),
+                (3, today(10, 0), true),
+                (4, today(10, 0), true),
+            ]
+        );
+    }
+}
@@ -14,19 +14,121 @@
  * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 
-use crate::utils::*;
+use crate::{
+    error::AppError,
+    media::Format,
+    schedule::{Schedule, ScheduleError},
+    timezone::{TimezoneError, Zone},
+    utils::*,
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::io::Read;
 use std::{
     fmt::{Display, Formatter},
-    fs::{File, OpenOptions},
+    fs::File,
     path::Path,
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
 };
 
+// Held from loading the settings until they are saved, so concurrent changes never overwrite
+// each other.
+static SETTINGS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// What to do with a reminder whose slot passed while the bot was down or offline.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum CatchUp {
+    Skip,
+    Late { within_minutes: u32 },
+    Always,
+}
+
+impl Default for CatchUp {
+    fn default() -> Self {
+        CatchUp::Late { within_minutes: 30 }
+    }
+}
+
+impl FromStr for CatchUp {
+    type Err = CatchUpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split_whitespace();
+        let policy = args.next().map(|p| p.to_ascii_lowercase());
+        let minutes = args.next();
+        if args.next().is_some() {
+            return Err(CatchUpError(String::from(s)));
+        }
+        match (policy.as_deref(), minutes) {
+            (Some("skip"), None) => Ok(CatchUp::Skip),
+            (Some("always"), None) => Ok(CatchUp::Always),
+            (Some("late"), None) => Ok(CatchUp::default()),
+            (Some("late"), Some(minutes)) => match minutes.parse::<u32>() {
+                Ok(within_minutes) if within_minutes > 0 => Ok(CatchUp::Late { within_minutes }),
+                _ => Err(CatchUpError(String::from(s))),
+            },
+            _ => Err(CatchUpError(String::from(s))),
+        }
+    }
+}
+
+impl Display for CatchUp {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            CatchUp::Skip => write!(f, "skip"),
+            CatchUp::Late { within_minutes } => {
+                write!(f, "late (within {} minutes)", within_minutes)
+            }
+            CatchUp::Always => write!(f, "always"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CatchUpError(pub String);
+
+impl Display for CatchUpError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Unknown catch-up policy '{}', expected 'skip', 'late <minutes>' or 'always'.",
+            self.0
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Subscribe {
     pub chat_id: i64,
     pub timezone: String,
+    #[serde(default)]
+    pub catch_up: CatchUp,
+    #[serde(default = "default_schedule")]
+    pub schedule: String,
+    /// Cleared when the bot can no longer post to the chat, reminders are paused until the
+    /// group subscribes again.
+    #[serde(default = "default_active")]
+    pub active: bool,
+    /// When the chat last (re)subscribed, reminders before it are never caught up.
+    #[serde(default)]
+    pub subscribed_at: Option<DateTime<Utc>>,
+    /// Voice packs reminders are drawn from, all clips when empty.
+    #[serde(default)]
+    pub packs: Vec<String>,
+    /// Kinds of extras sent along with each reminder, none when empty.
+    #[serde(default)]
+    pub extras: Vec<Format>,
+}
+
+fn default_schedule() -> String {
+    Schedule::default().to_string()
+}
+
+fn default_active() -> bool {
+    true
 }
 
 #[allow(dead_code)]
@@ -35,15 +137,42 @@ impl Subscribe {
         Subscribe {
             chat_id: id,
             timezone: String::from("UTC+8"),
+            catch_up: CatchUp::default(),
+            schedule: default_schedule(),
+            active: default_active(),
+            subscribed_at: Some(Utc::now()),
+            packs: vec![],
+            extras: vec![],
         }
     }
     pub(crate) fn update(&mut self, id: i64, tz: &str) {
         self.chat_id = id;
         self.timezone = String::from(tz);
     }
+    pub(crate) fn update_chat_id(&mut self, id: i64) {
+        self.chat_id = id;
+    }
     pub(crate) fn update_tz(&mut self, tz: &str) {
         self.timezone = String::from(tz);
     }
+    pub(crate) fn update_catch_up(&mut self, catch_up: CatchUp) {
+        self.catch_up = catch_up;
+    }
+    pub(crate) fn update_schedule(&mut self, schedule: &Schedule) {
+        self.schedule = schedule.to_string();
+    }
+    pub(crate) fn update_packs(&mut self, packs: Vec<String>) {
+        self.packs = packs;
+    }
+    pub(crate) fn update_extras(&mut self, extras: Vec<Format>) {
+        self.extras = extras;
+    }
+    pub fn zone(&self) -> Result<Zone, TimezoneError> {
+        self.timezone.parse::<Zone>()
+    }
+    pub fn schedule(&self) -> Result<Schedule, ScheduleError> {
+        self.schedule.parse::<Schedule>()
+    }
 }
 
 impl PartialEq for Subscribe {
@@ -54,69 +183,137 @@ impl PartialEq for Subscribe {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
+    #[serde(default)]
     pub subscribe: Vec<Subscribe>,
+    #[serde(default)]
     file_path: String,
 }
 
 #[allow(dead_code)]
 impl Settings {
-    pub fn new() -> Self {
-        let s = Self::make();
-        s.save();
-        s
-    }
-    pub(crate) fn save(&self) {
-        let path = self.get_file_path().expect("AppError::Settings::save");
-        let file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(path)
-            .expect("AppError::Settings::save");
-        serde_json::to_writer(file, &self).expect("AppError::Settings::save");
+    pub fn new() -> Result<Self, AppError> {
+        Self::make()
+    }
+    /// Takes the settings lock, hold it from `Settings::new()` until `save()` when changing them.
+    pub(crate) fn lock() -> MutexGuard<'static, ()> {
+        SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    pub(crate) fn save(&self) -> Result<(), AppError> {
+        let path = self.get_file_path()?;
+        write_atomic(Path::new(path), &serde_json::to_vec(&self)?)
     }
     pub(crate) fn add_sub(&mut self, val: Subscribe) -> &Self {
-        if !self.subscribe.contains(&val) {
-            self.subscribe.push(val)
+        match self.get_sub_mut(val.chat_id) {
+            Some(sub) if !sub.active => {
+                sub.active = true;
+                sub.subscribed_at = val.subscribed_at;
+            }
+            Some(_) => {}
+            None => self.subscribe.push(val),
         }
         self
     }
-    #[allow(unused_must_use)]
-    pub(crate) fn get_file_path(&self) -> core::result::Result<&str, SettingError> {
-        if self.file_path == "" {
-            Err::<&str, SettingError>(SettingError);
+    /// Moves a group's subscription to the supergroup it was upgraded to. Returns whether
+    /// anything changed, so the service message and the API error can both trigger it.
+    pub(crate) fn migrate_sub(&mut self, from: i64, to: i64) -> bool {
+        if self.get_sub(from).is_none() {
+            return false;
+        }
+        if self.get_sub(to).is_some() {
+            self.remove_sub(from);
+        } else if let Some(sub) = self.get_sub_mut(from) {
+            sub.update_chat_id(to);
+        }
+        true
+    }
+    pub(crate) fn deactivate_sub(&mut self, chat_id: i64) -> bool {
+        match self.get_sub_mut(chat_id) {
+            Some(sub) if sub.active => {
+                sub.active = false;
+                true
+            }
+            _ => false,
+        }
+    }
+    pub(crate) fn remove_sub(&mut self, chat_id: i64) -> Option<Subscribe> {
+        let index = self
+            .subscribe
+            .iter()
+            .position(|sub| sub.chat_id == chat_id)?;
+        Some(self.subscribe.remove(index))
+    }
+    pub(crate) fn get_sub(&self, chat_id: i64) -> Option<&Subscribe> {
+        self.subscribe.iter().find(|sub| sub.chat_id == chat_id)
+    }
+    pub(crate) fn get_sub_mut(&mut self, chat_id: i64) -> Option<&mut Subscribe> {
+        self.subscribe.iter_mut().find(|sub| sub.chat_id == chat_id)
+    }
+    pub(crate) fn get_file_path(&self) -> Result<&str, AppError> {
+        if self.file_path.is_empty() {
+            return Err(AppError::Storage(String::from(
+                "Setting file path does not exist, ensure call 'Settings::new()' first.",
+            )));
         }
         Ok(self.file_path.as_str())
     }
-    fn make() -> Self {
+    fn make() -> Result<Self, AppError> {
         let name = "Settings.json";
-        let dirs = get_setting_dir();
+        let dirs = get_setting_dir()?;
         let dir = dirs.config_dir();
         let path = format!("{}/{}", dir.display(), name);
-        let is_exists = check_exists_and_create(Path::new(&path.clone()), b"{}");
+        let is_exists = check_exists_and_create(Path::new(&path), b"{}")?;
 
-        if is_exists {
-            let mut f = File::open(path).unwrap();
+        let mut settings = if is_exists {
+            let mut f = File::open(&path)?;
             let mut buffer = String::new();
-            f.read_to_string(&mut buffer).unwrap();
-            let settings: Settings = serde_json::from_str(&buffer).unwrap();
-            settings
+            f.read_to_string(&mut buffer)?;
+            serde_json::from_str::<Settings>(&buffer)?
         } else {
             Settings {
                 subscribe: vec![],
-                file_path: path.clone(),
+                file_path: String::new(),
             }
-        }
+        };
+        settings.file_path = path;
+        Ok(settings)
     }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct SettingError;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl Display for SettingError {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "Setting file path does not exist, ensure call 'Settings::new()' first."
-        )
+    #[test]
+    fn parses_catch_up_policies() {
+        assert_eq!("skip".parse::<CatchUp>().unwrap(), CatchUp::Skip);
+        assert_eq!("Always".parse::<CatchUp>().unwrap(), CatchUp::Always);
+        assert_eq!("late".parse::<CatchUp>().unwrap(), CatchUp::default());
+        assert_eq!(
+            " late  90 ".parse::<CatchUp>().unwrap(),
+            CatchUp::Late { within_minutes: 90 }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_catch_up_policies() {
+        for s in &[
+            "",
+            "never",
+            "late 0",
+            "late -5",
+            "late soon",
+            "skip 5",
+            "late 5 6",
+        ] {
+            assert!(s.parse::<CatchUp>().is_err(), "{:?} should not parse", s);
+        }
+    }
+
+    #[test]
+    fn catch_up_round_trips_through_json() {
+        let policy = CatchUp::Late { within_minutes: 15 };
+        let json = serde_json::to_string(&policy).unwrap();
+        assert_eq!(json, r#"{"policy":"late","within_minutes":15}"#);
+        assert_eq!(serde_json::from_str::<CatchUp>(&json).unwrap(), policy);
     }
 }
@@ -0,0 +1,100 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{error::AppError, utils::*};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+// Loaded once, every send of a broadcast draws from and updates it.
+static SHUFFLE_BAGS: Lazy<Mutex<ShuffleBags>> = Lazy::new(|| {
+    Mutex::new(ShuffleBags::new().unwrap_or_else(|error| {
+        log::error!("{} Shuffle bags are kept in memory only.", error);
+        ShuffleBags::default()
+    }))
+});
+
+/// Clips each chat was sent since its shuffle bag was last refilled, none repeats until all
+/// were played.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShuffleBags {
+    #[serde(default)]
+    played: HashMap<i64, Vec<String>>,
+    #[serde(skip)]
+    file_path: String,
+}
+
+impl ShuffleBags {
+    pub fn new() -> Result<Self, AppError> {
+        Self::make()
+    }
+    /// The shared bags, the lock is held only while choosing or recording a clip.
+    pub(crate) fn lock() -> MutexGuard<'static, ShuffleBags> {
+        SHUFFLE_BAGS.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    pub(crate) fn save(&self) -> Result<(), AppError> {
+        if self.file_path.is_empty() {
+            return Ok(());
+        }
+        write_atomic(Path::new(&self.file_path), &serde_json::to_vec(&self)?)
+    }
+    pub(crate) fn played(&self, chat_id: i64) -> &[String] {
+        self.played.get(&chat_id).map_or(&[], Vec::as_slice)
+    }
+    pub(crate) fn played_mut(&mut self, chat_id: i64) -> &mut Vec<String> {
+        self.played.entry(chat_id).or_default()
+    }
+    pub(crate) fn migrate(&mut self, from: i64, to: i64) -> &Self {
+        if let Some(played) = self.played.remove(&from) {
+            self.played.entry(to).or_insert(played);
+        }
+        self
+    }
+    fn make() -> Result<Self, AppError> {
+        let name = "Shuffle.json";
+        let dirs = get_setting_dir()?;
+        let dir = dirs.data_dir();
+        let path = format!("{}/{}", dir.display(), name);
+        let is_exists = check_exists_and_create(Path::new(&path), b"{}")?;
+
+        let mut bags = if is_exists {
+            let mut f = File::open(&path)?;
+            let mut buffer = String::new();
+            f.read_to_string(&mut buffer)?;
+            serde_json::from_str::<ShuffleBags>(&buffer)?
+        } else {
+            ShuffleBags::default()
+        };
+        bags.file_path = path;
+        Ok(bags)
+    }
+}
+
+/// Persists the shuffle bags off the async workers. Each save writes the bags as they are when
+/// it runs, so the last one always has every recorded clip.
+pub(crate) fn save_in_background() {
+    tokio::task::spawn_blocking(|| {
+        if let Err(error) = ShuffleBags::lock().save() {
+            log::error!("{} Shuffle bags not persisted.", error);
+        }
+    });
+}
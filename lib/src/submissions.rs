@@ -0,0 +1,159 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::{error::AppError, utils::*};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, remove_file, write, File},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+// Held from loading the queue until it is saved, so two submissions never share an id and two
+// owners never review the same clip.
+static SUBMISSIONS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Clips one user may have waiting for review at a time.
+pub(crate) const MAX_PENDING_PER_USER: usize = 3;
+
+/// A clip sent in with /submit, waiting for an owner to approve or reject it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submission {
+    pub id: u64,
+    pub title: String,
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub user_name: String,
+    /// Downloaded clip, kept in the pending directory next to this file.
+    pub file: PathBuf,
+    pub extension: String,
+    pub submitted_at: DateTime<Utc>,
+}
+
+/// Persisted moderation queue of submitted clips.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Submissions {
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default)]
+    pending: Vec<Submission>,
+    #[serde(skip)]
+    file_path: String,
+}
+
+impl Submissions {
+    pub fn new() -> Result<Self, AppError> {
+        Self::make()
+    }
+    /// Takes the queue lock, hold it from `Submissions::new()` until `save()`.
+    pub(crate) fn lock() -> MutexGuard<'static, ()> {
+        SUBMISSIONS_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    pub(crate) fn save(&self) -> Result<(), AppError> {
+        write_atomic(Path::new(&self.file_path), &serde_json::to_vec(&self)?)
+    }
+    /// Stores the clip in the pending directory and queues it.
+    pub(crate) fn add(
+        &mut self,
+        title: &str,
+        chat_id: i64,
+        user_id: i64,
+        user_name: &str,
+        data: &[u8],
+        extension: &str,
+    ) -> Result<&Submission, AppError> {
+        let dir = Path::new(&self.file_path)
+            .parent()
+            .map(|dir| dir.join("pending"))
+            .ok_or_else(|| AppError::Storage(String::from("no directory for submissions")))?;
+        create_dir_all(&dir)?;
+        self.next_id += 1;
+        let file = dir.join(format!("{}.{}", self.next_id, extension));
+        write(&file, data)?;
+        self.pending.push(Submission {
+            id: self.next_id,
+            title: String::from(title),
+            chat_id,
+            user_id,
+            user_name: String::from(user_name),
+            file,
+            extension: String::from(extension),
+            submitted_at: Utc::now(),
+        });
+        Ok(&self.pending[self.pending.len() - 1])
+    }
+    /// Number of clips the user has waiting for review.
+    pub(crate) fn pending_from(&self, user_id: i64) -> usize {
+        self.pending.iter().filter(|s| s.user_id == user_id).count()
+    }
+    /// Takes the submission off the queue, its clip stays on disk until `discard`.
+    pub(crate) fn take(&mut self, id: u64) -> Option<Submission> {
+        let index = self.pending.iter().position(|s| s.id == id)?;
+        Some(self.pending.remove(index))
+    }
+    /// Queues a taken submission again, for one that could not be added after all.
+    pub(crate) fn put_back(&mut self, submission: Submission) -> &Self {
+        let index = self
+            .pending
+            .iter()
+            .position(|s| s.id > submission.id)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(index, submission);
+        self
+    }
+    fn make() -> Result<Self, AppError> {
+        let name = "Submissions.json";
+        let dirs = get_setting_dir()?;
+        let dir = dirs.data_dir();
+        let path = format!("{}/{}", dir.display(), name);
+        let is_exists = check_exists_and_create(Path::new(&path), b"{}")?;
+
+        let mut submissions = if is_exists {
+            let mut f = File::open(&path)?;
+            let mut buffer = String::new();
+            f.read_to_string(&mut buffer)?;
+            serde_json::from_str::<Submissions>(&buffer)?
+        } else {
+            Submissions {
+                next_id: 0,
+                pending: vec![],
+                file_path: String::new(),
+            }
+        };
+        submissions.file_path = path;
+        Ok(submissions)
+    }
+}
+
+impl Submission {
+    pub(crate) fn read(&self) -> Result<Vec<u8>, AppError> {
+        let mut data = vec![];
+        File::open(&self.file)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+    pub(crate) fn discard(&self) {
+        if let Err(error) = remove_file(&self.file) {
+            log::warn!(
+                "AppError::io: {} Submitted clip {} not removed.",
+                error,
+                self.file.display()
+            );
+        }
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+// Telegram allows about 30 messages per second overall and 20 per minute into one group.
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(34);
+const CHAT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Paces outgoing reminders so a broadcast to many chats stays under Telegram's flood limits.
+/// Every send reserves the next free slot of the global and its chat's bucket, and a
+/// `RetryAfter` from Telegram pauses all sends until it has passed.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_global: Option<Instant>,
+    next_chat: HashMap<i64, Instant>,
+    paused_until: Option<Instant>,
+}
+
+impl Throttle {
+    /// Waits until a message may be sent to the chat.
+    pub(crate) async fn acquire(&self, chat_id: i64) {
+        loop {
+            let at = self.reserve(chat_id);
+            tokio::time::sleep_until(at).await;
+            // A RetryAfter received while waiting moves every pending send behind it.
+            match self.paused_until() {
+                Some(until) if until > Instant::now() => continue,
+                _ => return,
+            }
+        }
+    }
+
+    /// Holds back every send for the given time, as asked by a `RetryAfter` error.
+    pub(crate) fn pause(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.paused_until = Some(state.paused_until.map_or(until, |paused| paused.max(until)));
+    }
+
+    fn paused_until(&self) -> Option<Instant> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .paused_until
+    }
+
+    fn reserve(&self, chat_id: i64) -> Instant {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.next_chat.retain(|_, next| *next > now);
+        let at = [
+            Some(now),
+            state.next_global,
+            state.paused_until,
+            state.next_chat.get(&chat_id).copied(),
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .max()
+        .unwrap_or(now);
+        state.next_global = Some(at + GLOBAL_INTERVAL);
+        state.next_chat.insert(chat_id, at + CHAT_INTERVAL);
+        at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_sends_across_chats() {
+        let throttle = Throttle::default();
+        let first = throttle.reserve(1);
+        let second = throttle.reserve(2);
+        let third = throttle.reserve(3);
+        assert_eq!(second - first, GLOBAL_INTERVAL);
+        assert_eq!(third - second, GLOBAL_INTERVAL);
+    }
+
+    #[test]
+    fn spaces_sends_into_one_chat() {
+        let throttle = Throttle::default();
+        let first = throttle.reserve(1);
+        let other = throttle.reserve(2);
+        let again = throttle.reserve(1);
+        assert_eq!(again - first, CHAT_INTERVAL);
+        assert!(other < again);
+    }
+
+    #[test]
+    fn pause_holds_back_every_chat() {
+        let throttle = Throttle::default();
+        let before = Instant::now();
+        throttle.pause(Duration::from_secs(10));
+        throttle.pause(Duration::from_secs(1));
+        assert!(throttle.reserve(1) >= before + Duration::from_secs(10));
+        assert!(throttle.reserve(2) >= before + Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_the_reserved_slot() {
+        let throttle = Throttle::default();
+        let start = Instant::now();
+        throttle.acquire(1).await;
+        throttle.acquire(2).await;
+        assert!(start.elapsed() >= GLOBAL_INTERVAL);
+    }
+}
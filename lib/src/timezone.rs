@@ -0,0 +1,205 @@
+/*
+ * Copyright 2021 Garfield Lee<opensource@550.moe>, Biscuit Tin
+ *
+ * The 3-Clause BSD License
+ *
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use chrono::{DateTime, FixedOffset, LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// A subscription timezone, either a fixed offset like `UTC+8` or an IANA name like
+/// `Asia/Hong_Kong`. Named zones follow their DST rules.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    pub fn local(&self, t: &DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Fixed(offset) => t.with_timezone(offset).naive_local(),
+            Zone::Named(tz) => t.with_timezone(tz).naive_local(),
+        }
+    }
+    /// Maps a local wall clock time back to UTC. Times skipped by a DST transition give `None`,
+    /// times repeated by one resolve to the earlier instant.
+    pub fn from_local(&self, t: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Fixed(offset) => earliest(offset.from_local_datetime(t)),
+            Zone::Named(tz) => earliest(tz.from_local_datetime(t)),
+        }
+    }
+}
+
+fn earliest<T: TimeZone>(t: LocalResult<DateTime<T>>) -> Option<DateTime<Utc>> {
+    match t {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.with_timezone(&Utc)),
+        LocalResult::None => None,
+    }
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Zone::Fixed(FixedOffset::east(8 * 3600)) // UTC+8
+    }
+}
+
+impl FromStr for Zone {
+    type Err = TimezoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(offset) = parse_fixed_offset(s) {
+            return Ok(Zone::Fixed(offset));
+        }
+        match s.parse::<Tz>() {
+            Ok(tz) => Ok(Zone::Named(tz)),
+            Err(_) => Err(TimezoneError(String::from(s))),
+        }
+    }
+}
+
+impl Display for Zone {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Zone::Fixed(offset) => write!(f, "UTC{}", offset),
+            Zone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+// Accepts "UTC", "GMT", "UTC+8", "GMT-03:30", "+0530" and friends.
+fn parse_fixed_offset(s: &str) -> Option<FixedOffset> {
+    let upper = s.to_ascii_uppercase();
+    let rest = match upper
+        .strip_prefix("UTC")
+        .or_else(|| upper.strip_prefix("GMT"))
+    {
+        Some("") => return FixedOffset::east_opt(0),
+        Some(rest) => rest,
+        None => upper.as_str(),
+    };
+
+    let (sign, digits) = match rest.chars().next()? {
+        '+' => (1, &rest[1..]),
+        '-' => (-1, &rest[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match digits.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if digits.len() > 2 => digits.split_at(digits.len() - 2),
+        None => (digits, "0"),
+    };
+    if hours.is_empty() || !hours.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    if minutes.is_empty() || !minutes.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+#[derive(Debug, Clone)]
+pub struct TimezoneError(pub String);
+
+impl Display for TimezoneError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Unknown timezone '{}', expected an offset like 'UTC+8' or a name like 'Asia/Hong_Kong'.",
+            self.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    #[test]
+    fn parses_fixed_offsets() {
+        let east = |secs| Zone::Fixed(FixedOffset::east(secs));
+        assert_eq!("UTC".parse::<Zone>().unwrap(), east(0));
+        assert_eq!("gmt".parse::<Zone>().unwrap(), east(0));
+        assert_eq!("UTC+8".parse::<Zone>().unwrap(), east(8 * 3600));
+        assert_eq!(
+            "GMT-03:30".parse::<Zone>().unwrap(),
+            east(-(3 * 3600 + 1800))
+        );
+        assert_eq!("+0530".parse::<Zone>().unwrap(), east(5 * 3600 + 1800));
+        assert_eq!(" utc+14 ".parse::<Zone>().unwrap(), east(14 * 3600));
+    }
+
+    #[test]
+    fn parses_named_zones() {
+        assert_eq!(
+            "Asia/Hong_Kong".parse::<Zone>().unwrap(),
+            Zone::Named(chrono_tz::Asia::Hong_Kong)
+        );
+        assert_eq!(
+            "Asia/Hong_Kong".parse::<Zone>().unwrap().to_string(),
+            "Asia/Hong_Kong"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_zones() {
+        for s in &["", "UTC+15", "UTC+8:60", "UTC+", "+ab", "Mars/Olympus", "8"] {
+            assert!(s.parse::<Zone>().is_err(), "{:?} should not parse", s);
+        }
+    }
+
+    #[test]
+    fn skipped_local_time_has_no_instant() {
+        // Clocks in New York jumped from 02:00 to 03:00 on 2021-03-14.
+        let zone = Zone::Named(chrono_tz::America::New_York);
+        assert_eq!(zone.from_local(&local(2021, 3, 14, 2, 30)), None);
+        assert_eq!(
+            zone.from_local(&local(2021, 3, 14, 3, 0)),
+            Some(Utc.ymd(2021, 3, 14).and_hms(7, 0, 0))
+        );
+    }
+
+    #[test]
+    fn repeated_local_time_resolves_to_earlier_instant() {
+        // Clocks in New York fell back from 02:00 to 01:00 on 2021-11-07.
+        let zone = Zone::Named(chrono_tz::America::New_York);
+        assert_eq!(
+            zone.from_local(&local(2021, 11, 7, 1, 30)),
+            Some(Utc.ymd(2021, 11, 7).and_hms(5, 30, 0))
+        );
+    }
+
+    #[test]
+    fn fixed_offset_round_trips() {
+        let zone = Zone::default();
+        let t = Utc.ymd(2021, 6, 1).and_hms(7, 0, 0);
+        assert_eq!(zone.local(&t), local(2021, 6, 1, 15, 0));
+        assert_eq!(zone.from_local(&zone.local(&t)), Some(t));
+    }
+}
@@ -14,66 +14,136 @@
  * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 
+use crate::error::AppError;
 use directories::ProjectDirs;
-use std::{fs, io::Write, path::Path};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use teloxide::{
     prelude::{AutoSend, Bot},
     requests::Requester,
-    types::{ChatMember, Me},
-    RequestError,
+    types::{ChatMember, Me, Message},
 };
 
-pub async fn display_bot_info(bot: &AutoSend<Bot>) {
-    let me: Result<Me, RequestError> = bot.get_me().await;
-    let me = match me {
-        Ok(me) => me,
-        Err(ref error) => {
-            log::error!("AppError::api: API error, details: {:?}", error);
-            me.unwrap()
-        }
-    };
+pub async fn display_bot_info(bot: &AutoSend<Bot>) -> Result<Me, AppError> {
+    let me = bot.get_me().await?;
     log::info!("Bot id:                 {}", me.user.id);
     log::info!("Bot name:               {}", me.user.first_name);
-    log::info!("Bot username:           {}", me.user.username.unwrap());
+    log::info!(
+        "Bot username:           {}",
+        me.user.username.as_deref().unwrap_or_default()
+    );
     log::info!("Bot can join groups:    {}", me.can_join_groups);
+    Ok(me)
 }
 
-#[allow(dead_code)]
-pub(crate) async fn check_sender_is_admin(bot: &AutoSend<Bot>, chat_id: i64) -> bool {
-    let chat_admin: Result<Vec<ChatMember>, RequestError> =
-        bot.get_chat_administrators(chat_id).await;
-    let chat_admin = match chat_admin {
-        Ok(admin) => admin,
-        Err(ref error) => {
-            log::error!("AppError::api: API error, details: {:?}", error);
-            chat_admin.unwrap()
-        }
-    };
-    println!("{}", chat_admin.first().unwrap().user.id);
-    return false;
+// How long a chat's administrator list is trusted before it is fetched again.
+const ADMIN_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+// Chat id to the time the list was fetched and the user ids of its administrators.
+type AdminCache = HashMap<i64, (Instant, Vec<i64>)>;
+
+static ADMIN_CACHE: Lazy<Mutex<AdminCache>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether the sender of `msg` may change this chat's settings. Anyone may in a private chat,
+/// in groups only the creator, administrators, and anonymous administrators posting as the
+/// group itself.
+pub(crate) async fn check_sender_is_admin(
+    bot: &AutoSend<Bot>,
+    msg: &Message,
+) -> Result<bool, AppError> {
+    if msg.chat.is_private() {
+        return Ok(true);
+    }
+    if let Some(sender_chat) = msg.sender_chat() {
+        return Ok(sender_chat.id == msg.chat.id);
+    }
+    match msg.from() {
+        Some(user) => check_user_is_admin(bot, msg.chat.id, user.id).await,
+        None => Ok(false),
+    }
+}
+
+pub(crate) async fn check_user_is_admin(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    user_id: i64,
+) -> Result<bool, AppError> {
+    let cached = ADMIN_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&chat_id)
+        .filter(|(fetched, _)| fetched.elapsed() < ADMIN_CACHE_TTL)
+        .map(|(_, admins)| admins.contains(&user_id));
+    if let Some(is_admin) = cached {
+        return Ok(is_admin);
+    }
+
+    let chat_admin: Vec<ChatMember> = bot.get_chat_administrators(chat_id).await?;
+    let admins: Vec<i64> = chat_admin
+        .iter()
+        .filter(|member| member.is_privileged())
+        .map(|member| member.user.id)
+        .collect();
+    log::trace!("Cached {} admin(s) of chat id: {}.", admins.len(), chat_id);
+    let is_admin = admins.contains(&user_id);
+    ADMIN_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(chat_id, (Instant::now(), admins));
+    Ok(is_admin)
+}
+
+/// User ids of the bot owners, listed comma separated in `BOT_OWNERS`.
+pub(crate) fn owners() -> Vec<i64> {
+    env::var("BOT_OWNERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse::<i64>().ok())
+        .collect()
 }
 
-pub(crate) fn get_setting_dir() -> ProjectDirs {
-    let dirs =
-        ProjectDirs::from("org", "BiscuitTin", "YinChaBot").expect("AppError::utils::directories");
-    return dirs;
+pub(crate) fn is_owner(user_id: i64) -> bool {
+    owners().contains(&user_id)
 }
 
-pub(crate) fn check_exists_and_create(p: &Path, buf: &[u8]) -> bool {
+pub(crate) fn get_setting_dir() -> Result<ProjectDirs, AppError> {
+    ProjectDirs::from("org", "BiscuitTin", "YinChaBot")
+        .ok_or_else(|| AppError::Storage(String::from("no home directory for settings")))
+}
+
+pub(crate) fn check_exists_and_create(p: &Path, buf: &[u8]) -> Result<bool, AppError> {
     if p.exists() {
-        return true;
+        return Ok(true);
     }
-    let name = p.file_name().expect("App::utils::create");
-    let dir = String::from(p.clone().to_str().unwrap()).replace(name.clone().to_str().unwrap(), "");
-    let dir_p = Path::new(&dir);
-    if !dir_p.exists() {
-        log::trace!("App::utils::create dir {}", dir);
-        let _d = fs::create_dir_all(dir_p); // If dir not exist, create first.
+    if let Some(dir_p) = p.parent() {
+        if !dir_p.exists() {
+            log::trace!("App::utils::create dir {}", dir_p.display());
+            fs::create_dir_all(dir_p)?; // If dir not exist, create first.
+        }
     }
     log::trace!("App::utils::create file: {}", p.display());
     // File also create first.
-    let mut _f = fs::File::create(p).expect("AppError::utils::create");
-    _f.write_all(buf).expect("AppError::utils::writeAll");
-    _f.sync_all().expect("AppError::utils::syncAll");
-    return false;
+    let mut f = fs::File::create(p)?;
+    f.write_all(buf)?;
+    f.sync_all()?;
+    Ok(false)
+}
+
+/// Replaces the file through a temporary copy next to it, so readers and a crash midway only
+/// ever see the old or the new contents.
+pub(crate) fn write_atomic(p: &Path, buf: &[u8]) -> Result<(), AppError> {
+    let mut tmp = p.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(buf)?;
+    f.sync_all()?;
+    fs::rename(&tmp, p)?;
+    Ok(())
 }
@@ -15,28 +15,100 @@
  */
 
 use dotenv::dotenv;
-use std::env;
-use teloxide::{prelude::Bot, requests::RequesterExt};
-use yinchabot::{handler::message_handler, listener::polling_listener, utils::display_bot_info};
+use std::{env, process};
+use teloxide::{
+    adaptors::AutoSend,
+    prelude::{
+        Bot, CallbackQuery, ChatMemberUpdated, Dispatcher, DispatcherHandlerRx,
+        LoggingErrorHandler, Message, OnError, StreamExt,
+    },
+    requests::RequesterExt,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use yinchabot::{
+    command::set_bot_commands,
+    error::AppError,
+    handler::{callback_query_handler, message_handler, my_chat_member_handler},
+    listener::polling_listener,
+    media::{check_extra_library, check_voice_library, spawn_media_watcher},
+    scheduler::spawn_scheduler,
+    utils::display_bot_info,
+};
 
 #[tokio::main]
 async fn main() {
-    dotenv().expect("AppError::env");
+    let env_loaded = dotenv();
     yinchabot::enable_logging!();
+    if let Err(error) = env_loaded {
+        log::warn!("AppError::env: .env not loaded, details: {}", error);
+    }
     log::trace!("Environment set, app starting...");
-    run_bot().await;
+    if let Err(error) = run_bot().await {
+        log::error!("{}", error);
+        process::exit(1);
+    }
 }
 
-async fn run_bot() {
+async fn run_bot() -> Result<(), AppError> {
     log::trace!("Bot starting...");
+    if env::var("TELOXIDE_TOKEN").is_err() {
+        return Err(AppError::Config(String::from("TELOXIDE_TOKEN not set!")));
+    }
     let bot = Bot::from_env();
     log::trace!("Bot created! Current api url: {}.", bot.api_url().as_str());
     let bot_inst = bot.auto_send();
     log::trace!("Bot auto send enabled!");
 
-    display_bot_info(&bot_inst).await;
+    // Converting clips can take a while, keep it off the async workers.
+    tokio::task::spawn_blocking(check_voice_library)
+        .await
+        .map_err(|error| AppError::Media(error.to_string()))??;
+    tokio::task::spawn_blocking(check_extra_library)
+        .await
+        .map_err(|error| AppError::Media(error.to_string()))?;
+    spawn_media_watcher();
 
-    let listener = polling_listener(bot_inst.clone());
+    let me = display_bot_info(&bot_inst).await?;
+    let bot_name = me.user.username.unwrap_or_default();
+    if let Err(error) = set_bot_commands(&bot_inst).await {
+        log::error!("{} Command menu not registered.", error);
+    }
 
-    teloxide::repl_with_listener(bot_inst.clone(), message_handler, listener).await;
+    let scheduler = spawn_scheduler(bot_inst.clone());
+    log::trace!("Reminder scheduler started!");
+
+    let listener = polling_listener(bot_inst.clone(), scheduler.clone());
+    let member_scheduler = scheduler.clone();
+    let callback_scheduler = scheduler.clone();
+
+    Dispatcher::new(bot_inst.clone())
+        .messages_handler(move |rx: DispatcherHandlerRx<AutoSend<Bot>, Message>| {
+            UnboundedReceiverStream::new(rx).for_each_concurrent(None, move |msg| {
+                let handled = message_handler(msg, scheduler.clone(), bot_name.clone());
+                async move { handled.await.log_on_error().await }
+            })
+        })
+        .my_chat_members_handler(
+            move |rx: DispatcherHandlerRx<AutoSend<Bot>, ChatMemberUpdated>| {
+                UnboundedReceiverStream::new(rx).for_each_concurrent(None, move |upd| {
+                    let handled = my_chat_member_handler(upd, member_scheduler.clone());
+                    async move { handled.await.log_on_error().await }
+                })
+            },
+        )
+        .callback_queries_handler(
+            move |rx: DispatcherHandlerRx<AutoSend<Bot>, CallbackQuery>| {
+                UnboundedReceiverStream::new(rx).for_each_concurrent(None, move |upd| {
+                    let handled = callback_query_handler(upd, callback_scheduler.clone());
+                    async move { handled.await.log_on_error().await }
+                })
+            },
+        )
+        .setup_ctrlc_handler()
+        .dispatch_with_listener(
+            listener,
+            LoggingErrorHandler::with_custom_text("An error from the update listener"),
+        )
+        .await;
+    Ok(())
 }